@@ -0,0 +1,170 @@
+// soccer 在 WebSocket 握手请求中携带签名令牌，goal 据此认证用户。
+//
+// 令牌格式（放在 `Authorization` 请求头中）：
+//
+//     Goal <user>:<timestamp>:<nonce>:<signature>
+//
+// `timestamp` 为 Unix 时间戳（秒），`nonce` 为随机的十六进制字符串，
+// `signature` 为 HMAC-SHA256(secret, "<user>:<timestamp>:<nonce>") 的十六进制编码。
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const HEADER_NAME: &str = "authorization";
+pub const SCHEME: &str = "Goal";
+
+const NONCE_LEN: usize = 16;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TokenError::Malformed => "malformed token",
+            TokenError::BadSignature => "bad token signature",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Token {
+    pub user: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    signature: Vec<u8>,
+}
+
+impl Token {
+    // 使用当前时间和随机 nonce 生成令牌
+    pub fn generate(user: &str, secret: &[u8]) -> Self {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce_bytes);
+
+        Self::sign(user, unix_timestamp(), &hex::encode(nonce_bytes), secret)
+    }
+
+    pub fn sign(user: &str, timestamp: u64, nonce: &str, secret: &[u8]) -> Self {
+        let signature = compute_signature(user, timestamp, nonce, secret);
+
+        Self {
+            user: user.to_string(),
+            timestamp,
+            nonce: nonce.to_string(),
+            signature,
+        }
+    }
+
+    // 解析 `Authorization` 请求头的值，不校验签名
+    pub fn parse(header_value: &str) -> Result<Self, TokenError> {
+        let credentials = header_value
+            .strip_prefix(SCHEME)
+            .and_then(|s| s.strip_prefix(' '))
+            .ok_or(TokenError::Malformed)?;
+
+        let mut parts = credentials.trim().split(':');
+        let user = parts.next().filter(|s| !s.is_empty()).ok_or(TokenError::Malformed)?;
+        let timestamp = parts.next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or(TokenError::Malformed)?;
+        let nonce = parts.next().filter(|s| !s.is_empty()).ok_or(TokenError::Malformed)?;
+        let signature = parts.next()
+            .and_then(|s| hex::decode(s).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        if parts.next().is_some() {
+            return Err(TokenError::Malformed);
+        }
+
+        Ok(Self {
+            user: user.to_string(),
+            timestamp,
+            nonce: nonce.to_string(),
+            signature,
+        })
+    }
+
+    pub fn verify(&self, secret: &[u8]) -> Result<(), TokenError> {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC can take key of any size");
+        mac.update(signed_content(&self.user, self.timestamp, &self.nonce).as_bytes());
+
+        // `verify_slice` 以常量时间比较签名
+        mac.verify_slice(&self.signature).map_err(|_| TokenError::BadSignature)
+    }
+
+    pub fn to_header_value(&self) -> String {
+        format!(
+            "{} {}",
+            SCHEME,
+            [
+                self.user.clone(),
+                self.timestamp.to_string(),
+                self.nonce.clone(),
+                hex::encode(&self.signature),
+            ].join(":"),
+        )
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn signed_content(user: &str, timestamp: u64, nonce: &str) -> String {
+    format!("{}:{}:{}", user, timestamp, nonce)
+}
+
+fn compute_signature(user: &str, timestamp: u64, nonce: &str, secret: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(signed_content(user, timestamp, nonce).as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let token = Token::sign("alice", 1700000000, "00ff", b"secret");
+        let header_value = token.to_header_value();
+        assert!(header_value.starts_with("Goal alice:1700000000:00ff:"));
+
+        let parsed = Token::parse(&header_value).unwrap();
+        assert_eq!(token, parsed);
+        assert_eq!(Ok(()), parsed.verify(b"secret"));
+        assert_eq!(Err(TokenError::BadSignature), parsed.verify(b"other secret"));
+    }
+
+    #[test]
+    fn tampered_token_test() {
+        let token = Token::sign("alice", 1700000000, "00ff", b"secret");
+        let tampered = token.to_header_value().replace("1700000000", "1700000001");
+
+        let parsed = Token::parse(&tampered).unwrap();
+        assert_eq!(Err(TokenError::BadSignature), parsed.verify(b"secret"));
+    }
+
+    #[test]
+    fn malformed_token_test() {
+        assert_eq!(Err(TokenError::Malformed), Token::parse("Basic YWxpY2U6c2VjcmV0"));
+        assert_eq!(Err(TokenError::Malformed), Token::parse("Goal alice:abc:00ff:00"));
+        assert_eq!(Err(TokenError::Malformed), Token::parse("Goal alice:1:00ff"));
+        assert_eq!(Err(TokenError::Malformed), Token::parse("Goal alice:1:00ff:zz"));
+        assert_eq!(Err(TokenError::Malformed), Token::parse("Goal alice:1:00ff:00:00"));
+    }
+}
@@ -17,6 +17,15 @@ pub fn encode_request(domain: &str) -> std::io::Result<Vec<u8>> {
     Ok(request_bytes)
 }
 
+// `qtype`: 1 为 A 记录，28 为 AAAA 记录
+pub fn encode_request_with_qtype(domain: &str, qtype: u16) -> std::io::Result<Vec<u8>> {
+    let request_msg = request_message::RequestMessage::with_qtype(domain, qtype);
+    let mut request_bytes: Vec<u8> = vec![];
+    request_msg.to_bytes(&mut request_bytes)?;
+
+    Ok(request_bytes)
+}
+
 pub fn decode_response(response_bytes: &[u8]) -> std::io::Result<ResponseMessage> {
     ResponseMessage::parse_response(response_bytes)
 }
@@ -11,9 +11,13 @@ pub struct Question {
 
 impl Question {
     pub fn new(domain: &str) -> Self {
+        Self::with_qtype(domain, 1)
+    }
+
+    pub fn with_qtype(domain: &str, qtype: u16) -> Self {
         Self {
             domain: domain.to_string(),
-            qtype: 1,
+            qtype,
             qclass: 1,
         }
     }
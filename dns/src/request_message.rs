@@ -14,6 +14,13 @@ impl RequestMessage {
         Self { header, question }
     }
 
+    pub fn with_qtype(domain: &str, qtype: u16) -> Self {
+        let header = Header::new();
+        let question = Question::with_qtype(domain, qtype);
+
+        Self { header, question }
+    }
+
     pub fn to_bytes(&self, bytes: &mut Vec<u8>) -> std::io::Result<()> {
         self.header.to_bytes(bytes)?;
         self.question.to_bytes(bytes)?;
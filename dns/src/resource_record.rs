@@ -1,6 +1,7 @@
 use crate::utils::qname_to_domain;
 use byteorder::{ReadBytesExt, BE};
 use std::io::{BufRead, Cursor};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -14,6 +15,27 @@ pub struct ResourceRecord {
 }
 
 impl ResourceRecord {
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    // A 与 AAAA 记录中的地址，其他类型（如 CNAME）返回 `None`
+    pub fn ip_address(&self) -> Option<IpAddr> {
+        match (self.rtype, self.rdata.len()) {
+            (1, 4) => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&self.rdata);
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            (28, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.rdata);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+
     pub fn parse_from_reader(rdr: &mut Cursor<&[u8]>) -> std::io::Result<Self> {
         // [192, 12]
         let first_byte = rdr.read_u8()?;
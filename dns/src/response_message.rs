@@ -2,6 +2,7 @@ use crate::header::Header;
 use crate::question::Question;
 use crate::resource_record::ResourceRecord;
 use std::io::Cursor;
+use std::net::IpAddr;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -46,7 +47,7 @@ impl ResponseMessage {
         let r = self.answer_records.first()?;
 
         let addr = [
-            *r.rdata.get(0)?,
+            *r.rdata.first()?,
             *r.rdata.get(1)?,
             *r.rdata.get(2)?,
             *r.rdata.get(3)?,
@@ -59,7 +60,7 @@ impl ResponseMessage {
         let r = self.answer_records.last()?;
 
         let addr = [
-            *r.rdata.get(0)?,
+            *r.rdata.first()?,
             *r.rdata.get(1)?,
             *r.rdata.get(2)?,
             *r.rdata.get(3)?,
@@ -68,11 +69,25 @@ impl ResponseMessage {
         Some(addr)
     }
 
+    // 所有 A 与 AAAA 记录中的地址，按应答中的顺序
+    pub fn ip_addresses(&self) -> Vec<IpAddr> {
+        self.answer_records.iter()
+            .filter_map(|r| r.ip_address())
+            .collect()
+    }
+
+    // A 与 AAAA 记录中最小的 TTL，没有这类记录时返回 `None`
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answer_records.iter()
+            .filter(|r| r.ip_address().is_some())
+            .map(|r| r.ttl())
+            .min()
+    }
 
     pub fn addresses(&self) -> Vec<Option<[u8; 4]>> {
         self.answer_records.iter().map(|r| {
             let addr = [
-                *r.rdata.get(0)?,
+                *r.rdata.first()?,
                 *r.rdata.get(1)?,
                 *r.rdata.get(2)?,
                 *r.rdata.get(3)?,
@@ -96,5 +111,22 @@ mod tests {
 
         let resp = ResponseMessage::parse_response(resp).unwrap();
         assert_eq!(None, resp.first_address());
+        assert!(resp.ip_addresses().is_empty());
+        assert_eq!(None, resp.min_ttl());
+    }
+
+    #[test]
+    fn test_ip_addresses() {
+        // response of "z.cn" AAAA, with a CNAME record followed by an AAAA record
+        let resp = &[
+            209, 183, 129, 128, 0, 1, 0, 2, 0, 0, 0, 0, // header
+            1, 122, 2, 99, 110, 0, 0, 28, 0, 1, // question
+            192, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 4, 1, 119, 192, 12, // CNAME "w.z.cn"
+            192, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16, 0x24, 0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, // AAAA
+        ];
+
+        let resp = ResponseMessage::parse_response(resp).unwrap();
+        assert_eq!(vec!["2408::1".parse::<IpAddr>().unwrap()], resp.ip_addresses());
+        assert_eq!(Some(60), resp.min_ttl());
     }
 }
@@ -1,10 +1,36 @@
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
 use domain_name_query_types::NameQuery;
+use crate::metrics;
 use crate::resolve;
-use crate::result_cache::ResultCache;
+use crate::result_cache::{self, ResultCache};
 
-type QueryResult = Option<IpAddr>;
+// 应答中的全部地址，没有记录时为空
+pub type QueryResult = Result<Vec<IpAddr>, QueryError>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum QueryError {
+    // 期限内没有收到应答
+    TimedOut,
+    // 网络或解析错误
+    Failed,
+}
+
+impl QueryError {
+    // 用作指标的标签
+    pub fn reason(&self) -> &'static str {
+        match self {
+            QueryError::TimedOut => "timeout",
+            QueryError::Failed => "error",
+        }
+    }
+}
+
+pub type CacheEntry = result_cache::CacheEntry<NameQuery, QueryResult>;
+
+// 缓存时间的上限，避免过长的 TTL 使结果长期不更新
+const MAX_CACHE_TTL_SECS: u32 = 3600;
 
 enum ActorMessage {
     Query {
@@ -14,6 +40,11 @@ enum ActorMessage {
     Notify {
         name_query: NameQuery,
         result: QueryResult,
+        // 解析失败或没有记录时为 `None`，不缓存
+        ttl: Option<Duration>,
+    },
+    Dump {
+        responder: oneshot::Sender<Vec<CacheEntry>>,
     },
 }
 
@@ -22,51 +53,84 @@ struct Actor {
     self_handle: ActorHandle,
 
     server_addr: SocketAddr,
-    result_cache: ResultCache<NameQuery>,
+    timeout: Duration,
+    result_cache: ResultCache<NameQuery, QueryResult>,
     counter: u64,
 }
 
 impl Actor {
-    pub fn new(receiver: mpsc::Receiver<ActorMessage>, handle: ActorHandle, server_addr: SocketAddr) -> Self {
+    pub fn new(receiver: mpsc::Receiver<ActorMessage>, handle: ActorHandle, server_addr: SocketAddr, timeout: Duration) -> Self {
         Self {
             receiver,
             self_handle: handle,
 
             server_addr,
+            timeout,
 
             result_cache: ResultCache::new(),
             counter: 0,
         }
     }
 
-    pub async fn handle_message(&mut self, msg: ActorMessage) -> () {
+    pub async fn handle_message(&mut self, msg: ActorMessage) {
         match msg {
             ActorMessage::Query { name_query, responder } => {
                 self.counter += 1;
+                metrics::DNS_QUERIES.inc();
                 let nq = name_query.clone();
 
                 let self_handle = self.self_handle.clone();
                 let server_addr = self.server_addr;
+                let timeout = self.timeout;
                 let f = || {
+                    metrics::DNS_LOOKUPS.inc();
                     tokio::spawn(async move {
-                        let ret = match resolve::resolve_domain(server_addr, name_query.name.as_str()).await {
-                            Ok(op_ip_addr) => { op_ip_addr }
-                            Err(_) => { None }
+                        // 失败时同样通知等待者，使查询中的缓存项被移除，之后的查询重新发起
+                        let (result, ttl) = match resolve::resolve(server_addr, &name_query, timeout).await {
+                            Ok(answer) => {
+                                let ttl = match answer.ttl {
+                                    Some(ttl) if !answer.addresses.is_empty() => {
+                                        Some(Duration::from_secs(ttl.min(MAX_CACHE_TTL_SECS) as u64))
+                                    }
+                                    _ => None,
+                                };
+                                (Ok(answer.addresses), ttl)
+                            }
+                            Err(e) => {
+                                tracing::debug!("Failed to resolve {:?}, err: {}", name_query, e);
+                                let error = match e.kind() {
+                                    std::io::ErrorKind::TimedOut => QueryError::TimedOut,
+                                    _ => QueryError::Failed,
+                                };
+                                metrics::DNS_FAILURES.with_label_values(&[error.reason()]).inc();
+                                (Err(error), None)
+                            }
                         };
 
-                        self_handle.notify(name_query, ret).await;
+                        self_handle.notify(name_query, result, ttl).await;
                     });
                 };
 
-                self.result_cache.subscribe(&nq, responder, f);
+                if self.result_cache.subscribe(&nq, responder, f) {
+                    metrics::DNS_CACHE_HITS.inc();
+                }
+            }
+            ActorMessage::Notify { name_query, result, ttl } => {
+                self.result_cache.notify(&name_query, result, ttl);
             }
-            ActorMessage::Notify { name_query, result } => {
-                self.result_cache.notify(&name_query, result);
+            ActorMessage::Dump { responder } => {
+                let _ = responder.send(self.result_cache.entries());
             }
         }
     }
 }
 
+// 由调用者在所请求的地址族都没有地址时记录，
+// 同时查询 A 与 AAAA 记录时，只有 IPv4 地址的域名不算
+pub fn record_no_answer() {
+    metrics::DNS_FAILURES.with_label_values(&["no_answer"]).inc();
+}
+
 async fn run_as_actor(mut actor: Actor) {
     while let Some(msg) = actor.receiver.recv().await {
         actor.handle_message(msg).await;
@@ -76,20 +140,31 @@ async fn run_as_actor(mut actor: Actor) {
 #[derive(Clone)]
 pub struct ActorHandle {
     sender: mpsc::Sender<ActorMessage>,
+    timeout: Duration,
 }
 
 impl ActorHandle {
-    pub fn new(server_addr: SocketAddr) -> Self {
+    // `timeout` 为每次查询等待 DNS 服务器应答的期限
+    pub fn new(server_addr: SocketAddr, timeout: Duration) -> Self {
         let (sender, receiver) = mpsc::channel(10);
-        let handle = Self { sender };
-        let actor = Actor::new(receiver, handle.clone(), server_addr);
+        let handle = Self { sender, timeout };
+        let actor = Actor::new(receiver, handle.clone(), server_addr, timeout);
         tokio::spawn(run_as_actor(actor));
 
         handle
     }
 
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
     // call
-    pub async fn query(&self, name_query: NameQuery) -> QueryResult {
+    pub async fn query(&self, name_query: NameQuery) -> Option<IpAddr> {
+        self.query_all(name_query).await.ok()?.into_iter().next()
+    }
+
+    // call，返回全部地址
+    pub async fn query_all(&self, name_query: NameQuery) -> QueryResult {
         tracing::debug!("DNS query, {:?}", name_query);
         let (sender, receiver) = oneshot::channel();
         let msg = ActorMessage::Query {
@@ -98,18 +173,29 @@ impl ActorHandle {
         };
 
         let _ = self.sender.send(msg).await;
-        let ret = receiver.await.unwrap();
-        ret
+        receiver.await.unwrap_or(Err(QueryError::Failed))
     }
 
     // cast
-    pub async fn notify(&self, name_query: NameQuery, result: QueryResult) {
+    pub async fn notify(&self, name_query: NameQuery, result: QueryResult, ttl: Option<Duration>) {
         let msg = ActorMessage::Notify {
             name_query,
             result,
+            ttl,
+        };
+
+        let _ = self.sender.send(msg).await;
+    }
+
+    // call，返回查询中的与未过期的缓存项
+    pub async fn dump(&self) -> Vec<CacheEntry> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = ActorMessage::Dump {
+            responder: sender,
         };
 
         let _ = self.sender.send(msg).await;
+        receiver.await.unwrap_or_default()
     }
 }
 
@@ -120,7 +206,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_foo() {
-        let handle = ActorHandle::new("114.114.114.114:53".parse().unwrap());
+        let handle = ActorHandle::new("114.114.114.114:53".parse().unwrap(), resolve::DEFAULT_TIMEOUT);
 
 
         let tasks = (0..5).map(|i| {
@@ -143,4 +229,19 @@ mod tests {
             let _ = t.await;
         }
     }
+
+    #[tokio::test]
+    async fn timeout_test() {
+        // 不应答的 DNS 服务器
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let handle = ActorHandle::new(server.local_addr().unwrap(), Duration::from_millis(50));
+
+        for _ in 0..2 {
+            let ret = tokio::time::timeout(Duration::from_secs(1), handle.query_all(NameQuery::a_record("example.com"))).await;
+            assert_eq!(Ok(Err(QueryError::TimedOut)), ret.map_err(|_| ()));
+
+            // 超时后不再留有查询中的缓存项
+            assert!(handle.dump().await.is_empty());
+        }
+    }
 }
@@ -1,3 +1,4 @@
 pub mod actor;
+mod metrics;
 pub mod resolve;
 mod result_cache;
@@ -0,0 +1,21 @@
+use telemetry::once_cell::sync::Lazy;
+use telemetry::prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+
+pub static DNS_QUERIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("dns_queries_total", "DNS queries received by the resolver actors").unwrap()
+});
+
+pub static DNS_CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("dns_cache_hits_total", "DNS queries answered from the cache").unwrap()
+});
+
+// 发往 DNS 服务器的查询，等待中的相同查询会合并为一个
+pub static DNS_LOOKUPS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("dns_lookups_total", "DNS lookups sent to the upstream server").unwrap()
+});
+
+// reason: "error" 为一次查询的网络或解析错误，"timeout" 为一次查询在期限内没有收到应答，
+// "no_answer" 为一次解析中所请求的地址族都没有地址（见 `actor::record_no_answer`）
+pub static DNS_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("dns_failures_total", "DNS lookups without any address", &["reason"]).unwrap()
+});
@@ -1,6 +1,8 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 use bytes::BytesMut;
 use tokio::net::UdpSocket;
+use domain_name_query_types::NameQuery;
 
 // https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
 //
@@ -8,22 +10,14 @@ use tokio::net::UdpSocket;
 // or UDP headers).
 const MAX_RESPONSE_SIZE: usize = 512;
 
+// 未指定时等待应答的期限
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub async fn resolve_domain(server_addr: SocketAddr, domain: &str) -> std::io::Result<Option<IpAddr>> {
     tracing::debug!("resolving domain: {}", domain);
 
     let request_bytes = dns::encode_request(domain).unwrap();
-
-    let local_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let sock = UdpSocket::bind(local_addr).await?;
-
-    let _send_size = sock.send_to(&request_bytes, server_addr).await?;
-
-    let mut resp_buf = BytesMut::with_capacity(MAX_RESPONSE_SIZE);
-    let response_size = sock.recv_buf(&mut resp_buf).await?;
-    let response_bytes = &resp_buf[0..response_size];
-    tracing::debug!("received udp response, length: {}, {:?}", response_size, response_bytes);
-
-    let resp = dns::decode_response(response_bytes)?;
+    let resp = exchange(server_addr, &request_bytes, DEFAULT_TIMEOUT).await?;
 
     match resp.last_address() {
         None => {
@@ -38,6 +32,45 @@ pub async fn resolve_domain(server_addr: SocketAddr, domain: &str) -> std::io::R
     }
 }
 
+// 应答中的全部地址及其中最小的 TTL（秒）
+#[derive(Debug, Default)]
+pub struct Answer {
+    pub addresses: Vec<IpAddr>,
+    pub ttl: Option<u32>,
+}
+
+// 按查询类型解析，`timeout` 内没有收到应答时返回 `ErrorKind::TimedOut`
+pub async fn resolve(server_addr: SocketAddr, name_query: &NameQuery, timeout: Duration) -> std::io::Result<Answer> {
+    tracing::debug!("resolving {:?}", name_query);
+
+    let request_bytes = dns::encode_request_with_qtype(name_query.name.as_str(), name_query.q_type.code())?;
+    let resp = exchange(server_addr, &request_bytes, timeout).await?;
+
+    Ok(Answer {
+        addresses: resp.ip_addresses(),
+        ttl: resp.min_ttl(),
+    })
+}
+
+// UDP 应答可能丢失，等待超过 `timeout` 时放弃
+async fn exchange(server_addr: SocketAddr, request_bytes: &[u8], timeout: Duration) -> std::io::Result<dns::ResponseMessage> {
+    let local_ip = match server_addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let sock = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+
+    let _send_size = sock.send_to(request_bytes, server_addr).await?;
+
+    let mut resp_buf = BytesMut::with_capacity(MAX_RESPONSE_SIZE);
+    let response_size = tokio::time::timeout(timeout, sock.recv_buf(&mut resp_buf)).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "DNS response timed out"))??;
+    let response_bytes = &resp_buf[0..response_size];
+    tracing::debug!("received udp response, length: {}, {:?}", response_size, response_bytes);
+
+    dns::decode_response(response_bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
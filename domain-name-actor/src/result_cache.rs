@@ -1,82 +1,135 @@
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::net::IpAddr;
+use std::time::Duration;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 
-enum CacheItem {
+// 缓存项数量超过该值时，清理已过期的结果
+const MIN_PRUNE_LEN: usize = 1024;
+
+enum CacheItem<V = Option<IpAddr>> {
     Pending {
-        responders: Vec<oneshot::Sender<Option<IpAddr>>>,
+        responders: Vec<oneshot::Sender<V>>,
     },
-    #[allow(dead_code)]
     Resolved {
-        result: Option<IpAddr>,
+        result: V,
+        expires_at: Instant,
     },
 }
 
-impl CacheItem {
-    fn new_pending(responder: oneshot::Sender<Option<IpAddr>>) -> Self {
+impl<V> CacheItem<V> {
+    fn new_pending(responder: oneshot::Sender<V>) -> Self {
         Self::Pending {
             responders: vec![responder],
         }
     }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match self {
+            CacheItem::Pending { .. } => false,
+            CacheItem::Resolved { expires_at, .. } => *expires_at <= now,
+        }
+    }
 }
 
-pub struct ResultCache<K>
+// 缓存内容的快照
+#[derive(Debug)]
+pub struct CacheEntry<K, V> {
+    pub key: K,
+    // 查询中时为 `None`
+    pub result: Option<V>,
+    // 结果的剩余有效期
+    pub ttl: Duration,
+}
+
+pub struct ResultCache<K, V = Option<IpAddr>>
     where K: Clone + Eq + Hash
 {
-    h_map: HashMap<K, CacheItem>,
+    h_map: HashMap<K, CacheItem<V>>,
+    prune_len: usize,
 }
 
-impl<K> ResultCache<K>
-    where K: Clone + Eq + Hash
+impl<K, V> ResultCache<K, V>
+    where K: Clone + Eq + Hash,
+          V: Clone,
 {
 
     pub fn new() -> Self {
         Self {
             h_map: HashMap::new(),
+            prune_len: MIN_PRUNE_LEN,
         }
     }
 
-    pub fn subscribe<F>(&mut self, key: &K, responder: oneshot::Sender<Option<IpAddr>>, f: F) -> ()
+    // 有未过期的结果时直接返回，否则等待查询结果；尚未开始查询时调用 `f` 发起查询。
+    // 返回是否命中缓存
+    pub fn subscribe<F>(&mut self, key: &K, responder: oneshot::Sender<V>, f: F) -> bool
     where F: FnOnce()
     {
         match self.h_map.get_mut(key) {
-            None => {
+            Some(CacheItem::Pending { responders }) => {
+                responders.push(responder);
+                false
+            }
+            Some(item @ CacheItem::Resolved { .. }) if !item.is_expired(Instant::now()) => {
+                if let CacheItem::Resolved { result, .. } = item {
+                    let _ = responder.send(result.clone());
+                }
+                true
+            }
+            _ => {
                 let new_item = CacheItem::new_pending(responder);
                 self.h_map.insert(key.clone(), new_item);
                 f();
-            }
-            Some(item) => {
-                match item {
-                    CacheItem::Pending { responders } => {
-                        responders.push(responder);
-                    }
-                    CacheItem::Resolved { .. } => {
-                        todo!("asdf")
-                    }
-                }
+                false
             }
         }
     }
 
-    pub fn notify(&mut self, key: &K, result: Option<IpAddr>) -> () {
-        if let Some(item) = self.h_map.remove(key) {
-            match item {
-                CacheItem::Pending { responders } => {
-                    for responder in responders {
-                        let _ = responder.send(result);
-                    }
-
-                    // // Mark the item as resolved
-                    // self.h_map.insert(key.clone(), CacheItem::Resolved { result });
-                }
-                CacheItem::Resolved { .. } => {
-                    todo!()
-                }
+    // 通知等待者，`ttl` 不为 `None` 时缓存结果
+    pub fn notify(&mut self, key: &K, result: V, ttl: Option<Duration>) {
+        if let Some(CacheItem::Pending { responders }) = self.h_map.remove(key) {
+            for responder in responders {
+                let _ = responder.send(result.clone());
             }
         }
+
+        if let Some(ttl) = ttl {
+            let now = Instant::now();
+            self.h_map.insert(key.clone(), CacheItem::Resolved { result, expires_at: now + ttl });
+            self.prune(now);
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if self.h_map.len() < self.prune_len {
+            return;
+        }
+
+        self.h_map.retain(|_, item| !item.is_expired(now));
+        self.prune_len = (self.h_map.len() * 2).max(MIN_PRUNE_LEN);
     }
 
+    // 查询中的与未过期的缓存项
+    pub fn entries(&self) -> Vec<CacheEntry<K, V>> {
+        let now = Instant::now();
+        self.h_map.iter()
+            .filter(|(_, item)| !item.is_expired(now))
+            .map(|(key, item)| match item {
+                CacheItem::Pending { .. } => CacheEntry {
+                    key: key.clone(),
+                    result: None,
+                    ttl: Duration::ZERO,
+                },
+                CacheItem::Resolved { result, expires_at } => CacheEntry {
+                    key: key.clone(),
+                    result: Some(result.clone()),
+                    ttl: *expires_at - now,
+                },
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -97,7 +150,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_dns_cache() {
-        let mut c: ResultCache<String> = ResultCache { h_map: HashMap::new() };
+        let mut c: ResultCache<String> = ResultCache::new();
 
         let (sender1, receiver1) = oneshot::channel::<Option<IpAddr>>();
         let (sender2, receiver2) = oneshot::channel::<Option<IpAddr>>();
@@ -106,7 +159,7 @@ mod tests {
 
         tokio::spawn(async move {
             let result = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
-            c.notify(&"a".into(), Some(result));
+            c.notify(&"a".into(), Some(result), None);
         });
 
         let expected = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
@@ -116,4 +169,33 @@ mod tests {
         let ret2 = receiver2.await.unwrap().unwrap();
         assert_eq!(expected, ret2);
     }
+
+    #[tokio::test]
+    async fn test_cached_result() {
+        let mut c: ResultCache<String> = ResultCache::new();
+        let result = Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let (sender1, receiver1) = oneshot::channel::<Option<IpAddr>>();
+        c.subscribe(&String::from("a"), sender1, || {});
+        c.notify(&"a".into(), result, Some(Duration::from_secs(60)));
+        assert_eq!(result, receiver1.await.unwrap());
+
+        // 缓存有效期内不再发起查询
+        let (sender2, receiver2) = oneshot::channel::<Option<IpAddr>>();
+        assert!(c.subscribe(&String::from("a"), sender2, || panic!("should be cached")));
+        assert_eq!(result, receiver2.await.unwrap());
+
+        let entries = c.entries();
+        assert_eq!(1, entries.len());
+        assert_eq!(Some(result), entries[0].result);
+        assert!(entries[0].ttl <= Duration::from_secs(60));
+
+        // 过期后重新查询
+        c.notify(&"a".into(), result, Some(Duration::ZERO));
+        assert!(c.entries().is_empty());
+        let (sender3, _receiver3) = oneshot::channel::<Option<IpAddr>>();
+        let mut queried = false;
+        c.subscribe(&String::from("a"), sender3, || queried = true);
+        assert!(queried);
+    }
 }
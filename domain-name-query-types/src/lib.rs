@@ -3,7 +3,18 @@ use std::fmt;
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum QType {
     A,
-    // Aaaa,
+    Aaaa,
+}
+
+impl QType {
+    // https://datatracker.ietf.org/doc/html/rfc1035#section-3.2.2
+    // https://datatracker.ietf.org/doc/html/rfc3596#section-2.1
+    pub fn code(&self) -> u16 {
+        match self {
+            QType::A => 1,
+            QType::Aaaa => 28,
+        }
+    }
 }
 
 impl fmt::Display for QType {
@@ -12,9 +23,9 @@ impl fmt::Display for QType {
             QType::A => {
                 "A"
             }
-            // QType::Aaaa => {
-            //     "AAAA"
-            // }
+            QType::Aaaa => {
+                "AAAA"
+            }
         };
         write!(f, "{}", s)
     }
@@ -35,6 +46,13 @@ impl NameQuery {
             q_type: QType::A,
         }
     }
+
+    pub fn aaaa_record(name_str: &str) -> Self {
+        Self {
+            name: Name::from(name_str),
+            q_type: QType::Aaaa,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -43,5 +61,12 @@ mod tests {
 
     #[test]
     fn it_works() {
+        let name_query = NameQuery::a_record("z.cn");
+        assert_eq!("z.cn", name_query.name);
+        assert_eq!("A", name_query.q_type.to_string());
+
+        let name_query = NameQuery::aaaa_record("z.cn");
+        assert_eq!("AAAA", name_query.q_type.to_string());
+        assert_eq!(28, name_query.q_type.code());
     }
 }
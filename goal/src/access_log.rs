@@ -0,0 +1,346 @@
+// 隧道的访问日志，每条隧道结束时写一行 JSON
+//
+// 文件超过大小上限或进入新的周期（按 UTC 对齐，如每天零点）时轮转：
+// 当前文件改名为 "<path>.20261019T090913"，再打开新的文件。
+// 写入与轮转都在单独的线程中进行，慢速的磁盘不会阻塞处理连接的任务
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use configuration::AccessLogConfiguration;
+use transfer::traffic::RelaySummary;
+use crate::session::Session;
+
+#[derive(Debug, Default, Serialize)]
+pub struct AccessRecord {
+    pub session_id: u64,
+    // RFC 3339，UTC
+    pub started_at: String,
+    pub ended_at: String,
+    pub client_addr: String,
+    pub user: Option<String>,
+    pub path: String,
+    // 请求头中的目标
+    pub domain: Option<String>,
+    pub port: Option<u16>,
+    // 目标的 IP 地址，由上游代理解析域名时未知
+    pub resolved_addr: Option<String>,
+    pub upstream: Option<String>,
+    // "ok" 或失败的类别，如 "unresolved"、"denied"、"idle_timeout"、"stopped"
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    pub upload_bytes: u64,
+    pub upload_messages: u64,
+    pub download_bytes: u64,
+    pub download_messages: u64,
+    // 各方向结束的原因，如 "eof"、"half_close"、"error"，隧道建立之前失败时为 `None`
+    pub upload_end: Option<&'static str>,
+    pub download_end: Option<&'static str>,
+    pub duration_ms: u64,
+    #[serde(skip)]
+    started: Option<(SystemTime, Instant)>,
+}
+
+impl AccessRecord {
+    pub fn new(session: &Session) -> Self {
+        let now = SystemTime::now();
+        Self {
+            session_id: session.id,
+            started_at: humantime::format_rfc3339_millis(now).to_string(),
+            client_addr: session.client_addr.to_string(),
+            user: session.user.clone(),
+            path: session.path.clone(),
+            started: Some((now, Instant::now())),
+            ..Self::default()
+        }
+    }
+
+    // 隧道结束时记录结果与流量
+    pub fn finish(&mut self, outcome: &'static str, session: &Session) {
+        let (started_at, started) = self.started.unwrap_or((SystemTime::now(), Instant::now()));
+        let duration = started.elapsed();
+        self.ended_at = humantime::format_rfc3339_millis(started_at + duration).to_string();
+        self.duration_ms = duration.as_millis() as u64;
+        self.outcome = outcome;
+        let info = session.info();
+        self.upload_bytes = info.upload_bytes;
+        self.upload_messages = info.upload_messages;
+        self.download_bytes = info.download_bytes;
+        self.download_messages = info.download_messages;
+    }
+
+    pub fn set_ends(&mut self, summary: &RelaySummary) {
+        self.upload_end = Some(summary.ws_to_tcp.end.as_str());
+        self.download_end = Some(summary.tcp_to_ws.end.as_str());
+    }
+}
+
+// 等待写入的记录数上限，写入跟不上时丢弃新的记录
+const QUEUE_CAPACITY: usize = 4096;
+
+#[derive(Clone)]
+pub struct AccessLog {
+    writer: Arc<Writer>,
+}
+
+struct Writer {
+    // `close` 时取出，写入线程写完队列中的记录后退出
+    sender: Mutex<Option<SyncSender<Vec<u8>>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+    pub fn open(access_log_config: &AccessLogConfiguration) -> io::Result<Self> {
+        let file = RotatingFile::open(
+            access_log_config.path.clone(),
+            access_log_config.max_size_bytes,
+            access_log_config.rotate_interval_secs,
+            access_log_config.max_files,
+            SystemTime::now(),
+        )?;
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_CAPACITY);
+        let thread = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                let mut file = file;
+                for line in receiver {
+                    if let Err(e) = file.write_line(&line, SystemTime::now()) {
+                        tracing::error!("Failed to write access log, err: {}", e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            writer: Arc::new(Writer {
+                sender: Mutex::new(Some(sender)),
+                thread: Mutex::new(Some(thread)),
+            }),
+        })
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to serialize access record, err: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let sender = self.writer.sender.lock().unwrap();
+        let sender = match sender.as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        match sender.try_send(line) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => tracing::warn!("Access log queue is full, record of session {} dropped", record.session_id),
+            Err(TrySendError::Disconnected(_)) => tracing::error!("Access log writer exited"),
+        }
+    }
+
+    // 退出前调用，等待队列中的记录写入文件
+    pub fn close(&self) {
+        self.writer.sender.lock().unwrap().take();
+        if let Some(thread) = self.writer.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    interval_secs: Option<u64>,
+    // 当前文件所属的周期
+    period: u64,
+    max_files: Option<usize>,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: Option<u64>,
+        interval_secs: Option<u64>,
+        max_files: Option<usize>,
+        now: SystemTime,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        // 沿用已有的文件时按修改时间确定周期，以便重启后跨过周期时也能轮转
+        let modified = file.metadata()?.modified().unwrap_or(now);
+        let period = period_of(modified, interval_secs);
+
+        Ok(Self { path, file, size, max_size, interval_secs, period, max_files })
+    }
+
+    fn write_line(&mut self, line: &[u8], now: SystemTime) -> io::Result<()> {
+        let size_exceeded = self.max_size.is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        let period = period_of(now, self.interval_secs);
+        if size_exceeded || (period != self.period && self.size > 0) {
+            self.rotate(now)?;
+        }
+        self.period = period;
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        // "2026-10-19T09:09:13Z" ==> "20261019T090913"
+        let timestamp: String = humantime::format_rfc3339_seconds(now).to_string()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() && *c != 'Z')
+            .collect();
+        let mut rotated = suffixed(&self.path, &timestamp);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = suffixed(&self.path, &format!("{}.{}", timestamp, n));
+            n += 1;
+        }
+
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        tracing::info!("Rotated access log to {:?}", rotated);
+
+        if let Some(max_files) = self.max_files {
+            self.prune(max_files)?;
+        }
+        Ok(())
+    }
+
+    // 只保留最新的 `max_files` 个已轮转的文件
+    fn prune(&self, max_files: usize) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(()),
+        };
+
+        let mut rotated: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        // 文件名中的时间戳可以按字符串排序
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(max_files);
+        for path in &rotated[..excess] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn period_of(time: SystemTime, interval_secs: Option<u64>) -> u64 {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    match interval_secs {
+        Some(interval) if interval > 0 => secs / interval,
+        _ => 0,
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".");
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::session::Sessions;
+
+    #[test]
+    fn record_test() {
+        let sessions = Arc::new(Sessions::new());
+        let session = sessions.register("127.0.0.1:50000".parse().unwrap(), Some("alice".to_string()), "/goal".to_string());
+        let mut record = AccessRecord::new(&session);
+        record.domain = Some("example.com".to_string());
+        record.port = Some(443);
+        record.finish("unresolved", &session);
+
+        let json: serde_json::Value = serde_json::from_slice(&serde_json::to_vec(&record).unwrap()).unwrap();
+        assert_eq!(session.id, json["session_id"].as_u64().unwrap());
+        assert_eq!("alice", json["user"]);
+        assert_eq!("example.com", json["domain"]);
+        assert_eq!("unresolved", json["outcome"]);
+        assert_eq!(0, json["upload_messages"].as_u64().unwrap());
+        assert!(json["upload_end"].is_null());
+        assert!(json["started_at"].as_str().unwrap().ends_with('Z'));
+        assert!(json.get("started").is_none());
+    }
+
+    #[test]
+    fn log_test() {
+        let path = std::env::temp_dir().join(format!("goal_access_log_writer_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let access_log_config = AccessLogConfiguration {
+            path: path.clone(),
+            max_size_bytes: None,
+            rotate_interval_secs: None,
+            max_files: None,
+        };
+        let access_log = AccessLog::open(&access_log_config).unwrap();
+
+        let sessions = Arc::new(Sessions::new());
+        let session = sessions.register("127.0.0.1:50000".parse().unwrap(), None, "/goal".to_string());
+        let mut record = AccessRecord::new(&session);
+        record.finish("ok", &session);
+        access_log.clone().log(&record);
+        access_log.close();
+        // 关闭之后的记录被忽略
+        access_log.log(&record);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(1, content.lines().count());
+        assert!(content.contains("\"outcome\":\"ok\""));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rotate_test() {
+        let dir = std::env::temp_dir().join(format!("goal_access_log_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotated_count = || std::fs::read_dir(&dir).unwrap().count() - 1;
+
+        let now = UNIX_EPOCH + Duration::from_secs(1760000000);
+        let mut file = RotatingFile::open(path.clone(), Some(10), Some(3600), Some(2), now).unwrap();
+        file.write_line(b"12345678\n", now).unwrap();
+        assert_eq!(0, rotated_count());
+
+        // 超过大小上限
+        file.write_line(b"12345678\n", now).unwrap();
+        assert_eq!(1, rotated_count());
+
+        // 同一秒内再次轮转
+        file.write_line(b"12345678\n", now).unwrap();
+        assert_eq!(2, rotated_count());
+
+        // 进入新的周期，只保留 2 个已轮转的文件
+        file.write_line(b"1\n", now + Duration::from_secs(3600)).unwrap();
+        assert_eq!(2, rotated_count());
+        assert_eq!("1\n", std::fs::read_to_string(&path).unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
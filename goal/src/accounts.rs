@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use configuration::{AuthConfiguration, UserConfiguration};
+use crate::rate_limit::RateLimiter;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum AdmissionError {
+    Expired,
+    TooManyTunnels,
+    QuotaExceeded,
+}
+
+impl fmt::Display for AdmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AdmissionError::Expired => "account expired",
+            AdmissionError::TooManyTunnels => "too many tunnels",
+            AdmissionError::QuotaExceeded => "monthly quota exceeded",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// 某个月已使用的流量
+struct MonthlyUsage {
+    // "2024-05"
+    period: String,
+    bytes: u64,
+}
+
+pub struct Account {
+    name: String,
+    max_tunnels: Option<usize>,
+    monthly_quota_bytes: Option<u64>,
+    // 到期时刻的 Unix 时间戳
+    expires_at: Option<u64>,
+    // 重新加载配置时，新旧 `Account` 共用隧道数与流量；速率不变时也共用令牌桶，
+    // 否则已有的隧道与新的隧道各用一个令牌桶，合计的速率会超过限制
+    upload_limiter: Option<Arc<RateLimiter>>,
+    download_limiter: Option<Arc<RateLimiter>>,
+    active_tunnels: Arc<AtomicUsize>,
+    usage: Arc<Mutex<MonthlyUsage>>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl Account {
+    fn new(user_config: &UserConfiguration, dirty: Arc<AtomicBool>, now: u64) -> Result<Self, String> {
+        let expires_at = match &user_config.expires_at {
+            Some(s) => Some(
+                parse_date(s).ok_or_else(|| format!("invalid expires_at '{}' of user '{}'", s, user_config.name))?
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            name: user_config.name.clone(),
+            max_tunnels: user_config.max_tunnels,
+            monthly_quota_bytes: user_config.monthly_quota_bytes,
+            expires_at,
+            upload_limiter: user_config.upload_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            download_limiter: user_config.download_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+
+            active_tunnels: Arc::new(AtomicUsize::new(0)),
+            usage: Arc::new(Mutex::new(MonthlyUsage {
+                period: month_period(now),
+                bytes: 0,
+            })),
+            dirty,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn upload_limiter(&self) -> Option<&RateLimiter> {
+        self.upload_limiter.as_deref()
+    }
+
+    pub fn download_limiter(&self) -> Option<&RateLimiter> {
+        self.download_limiter.as_deref()
+    }
+
+    pub fn active_tunnels(&self) -> usize {
+        self.active_tunnels.load(Ordering::SeqCst)
+    }
+
+    // 检查账号是否可以再建立一条隧道，可以则占用一个名额，`TunnelLease` 被 drop 时归还
+    pub fn admit(self: &Arc<Self>, now: u64) -> Result<TunnelLease, AdmissionError> {
+        if let Some(expires_at) = self.expires_at {
+            if now >= expires_at {
+                return Err(AdmissionError::Expired);
+            }
+        }
+
+        if self.is_over_quota(now) {
+            return Err(AdmissionError::QuotaExceeded);
+        }
+
+        let max_tunnels = self.max_tunnels.unwrap_or(usize::MAX);
+        let admitted = self.active_tunnels.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            if n < max_tunnels {
+                Some(n + 1)
+            } else {
+                None
+            }
+        });
+        if admitted.is_err() {
+            return Err(AdmissionError::TooManyTunnels);
+        }
+
+        Ok(TunnelLease {
+            account: self.clone(),
+        })
+    }
+
+    // 记录流量，返回是否超出了本月配额
+    pub fn add_usage(&self, bytes: u64, now: u64) -> bool {
+        let period = month_period(now);
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period != period {
+            usage.period = period;
+            usage.bytes = 0;
+        }
+        usage.bytes += bytes;
+        self.dirty.store(true, Ordering::SeqCst);
+
+        match self.monthly_quota_bytes {
+            Some(quota) => usage.bytes >= quota,
+            None => false,
+        }
+    }
+
+    pub fn usage_bytes(&self, now: u64) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        if usage.period == month_period(now) {
+            usage.bytes
+        } else {
+            0
+        }
+    }
+
+    fn is_over_quota(&self, now: u64) -> bool {
+        match self.monthly_quota_bytes {
+            Some(quota) => self.usage_bytes(now) >= quota,
+            None => false,
+        }
+    }
+}
+
+// 占用账号的一个隧道名额
+pub struct TunnelLease {
+    account: Arc<Account>,
+}
+
+impl TunnelLease {
+    pub fn account(&self) -> &Arc<Account> {
+        &self.account
+    }
+}
+
+impl Drop for TunnelLease {
+    fn drop(&mut self) {
+        self.account.active_tunnels.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct Accounts {
+    accounts: HashMap<String, Arc<Account>>,
+    usage_file: Option<PathBuf>,
+    dirty: Arc<AtomicBool>,
+    // 重新加载配置前后的 `Accounts` 共用，同一时间只有一次保存在写临时文件
+    save_lock: Arc<Mutex<()>>,
+}
+
+impl Accounts {
+    pub fn new(auth_config: &AuthConfiguration, now: u64) -> Result<Self, String> {
+        let dirty = Arc::new(AtomicBool::new(false));
+
+        let mut accounts = HashMap::new();
+        for user_config in &auth_config.users {
+            let account = Account::new(user_config, dirty.clone(), now)?;
+            accounts.insert(user_config.name.clone(), Arc::new(account));
+        }
+
+        let accounts = Self {
+            accounts,
+            usage_file: auth_config.usage_file.clone(),
+            dirty,
+            save_lock: Arc::new(Mutex::new(())),
+        };
+
+        if let Some(path) = &accounts.usage_file {
+            accounts.load_usage(path)
+                .map_err(|e| format!("failed to load usage file {:?}, err: {}", path, e))?;
+        }
+
+        Ok(accounts)
+    }
+
+    // 按新的配置重建，已有用户的隧道数、流量以及速率未变的令牌桶保持不变
+    pub fn reload(&self, auth_config: &AuthConfiguration, now: u64) -> Result<Self, String> {
+        let mut accounts = HashMap::new();
+        for user_config in &auth_config.users {
+            let mut account = Account::new(user_config, self.dirty.clone(), now)?;
+            if let Some(old) = self.accounts.get(&user_config.name) {
+                account.active_tunnels = old.active_tunnels.clone();
+                account.usage = old.usage.clone();
+                carry_over(&mut account.upload_limiter, &old.upload_limiter);
+                carry_over(&mut account.download_limiter, &old.download_limiter);
+            }
+            accounts.insert(user_config.name.clone(), Arc::new(account));
+        }
+
+        Ok(Self {
+            accounts,
+            usage_file: auth_config.usage_file.clone(),
+            dirty: self.dirty.clone(),
+            save_lock: self.save_lock.clone(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Account>> {
+        self.accounts.get(name)
+    }
+
+    // 流量有变化时写入文件，写入失败时保留变化的标记，下次再写。
+    // 会同步到磁盘，在异步任务中应经 `spawn_blocking` 调用
+    pub fn save_usage(&self) -> std::io::Result<()> {
+        let path = match &self.usage_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _guard = self.save_lock.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let ret = self.write_usage(path);
+        if ret.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        ret
+    }
+
+    fn write_usage(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp_path = path.to_path_buf();
+        tmp_path.set_extension("tmp");
+
+        let mut f = File::create(&tmp_path)?;
+        for account in self.accounts.values() {
+            let usage = account.usage.lock().unwrap();
+            writeln!(f, "{} {} {}", account.name, usage.period, usage.bytes)?;
+        }
+        f.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    // 定期保存流量，避免 goal 重启后流量清零；重新加载配置后由新的 `Accounts` 接替
+    pub fn spawn_persist(self: &Arc<Self>, interval: std::time::Duration) -> Option<tokio::task::JoinHandle<()>> {
+        self.usage_file.as_ref()?;
+
+        let accounts = self.clone();
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                accounts.clone().save_usage_blocking().await;
+            }
+        });
+        Some(handle)
+    }
+
+    // 在阻塞线程池中保存流量，不占用处理连接的线程
+    pub async fn save_usage_blocking(self: Arc<Self>) {
+        match tokio::task::spawn_blocking(move || self.save_usage()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::error!("Failed to save usage file, err: {}", e),
+            Err(e) => tracing::error!("Failed to save usage file, err: {}", e),
+        }
+    }
+
+    fn load_usage(&self, path: &Path) -> std::io::Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let f = File::open(path)?;
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let parts: Vec<&str> = line.split(' ').collect();
+            if let [name, period, bytes] = parts[..] {
+                let bytes = match bytes.parse::<u64>() {
+                    Ok(bytes) => bytes,
+                    Err(_) => continue,
+                };
+                if let Some(account) = self.accounts.get(name) {
+                    let mut usage = account.usage.lock().unwrap();
+                    if usage.period == period {
+                        usage.bytes = bytes;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// "2024-05-31" ==> 当天零点（UTC）的 Unix 时间戳
+pub fn parse_date(s: &str) -> Option<u64> {
+    let parts: Vec<&str> = s.trim().split('-').collect();
+    if let [y, m, d] = parts[..] {
+        let y = y.parse::<i64>().ok()?;
+        let m = m.parse::<u32>().ok().filter(|m| (1..=12).contains(m))?;
+        let d = d.parse::<u32>().ok().filter(|d| (1..=31).contains(d))?;
+
+        let days = days_from_civil(y, m, d);
+        u64::try_from(days).ok().map(|days| days * SECS_PER_DAY)
+    } else {
+        None
+    }
+}
+
+// Unix 时间戳 ==> "2024-05"
+pub fn month_period(unix_secs: u64) -> String {
+    let (y, m, _) = civil_from_days((unix_secs / SECS_PER_DAY) as i64);
+    format!("{:04}-{:02}", y, m)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+fn carry_over(limiter: &mut Option<Arc<RateLimiter>>, old: &Option<Arc<RateLimiter>>) {
+    if let (Some(new), Some(old)) = (limiter.as_ref(), old) {
+        if new.rate() == old.rate() {
+            *limiter = Some(old.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_config(name: &str) -> UserConfiguration {
+        UserConfiguration {
+            name: name.to_string(),
+            secret: "secret".to_string(),
+            max_tunnels: Some(1),
+            monthly_quota_bytes: Some(100),
+            upload_rate: None,
+            download_rate: None,
+            expires_at: Some("2024-06-01".to_string()),
+            outbound: None,
+        }
+    }
+
+    #[test]
+    fn date_test() {
+        assert_eq!(Some(0), parse_date("1970-01-01"));
+        assert_eq!(Some(1717200000), parse_date("2024-06-01"));
+        assert_eq!(None, parse_date("2024-13-01"));
+        assert_eq!(None, parse_date("2024/06/01"));
+
+        assert_eq!("1970-01", month_period(0));
+        assert_eq!("2024-05", month_period(1717200000 - 1));
+        assert_eq!("2024-06", month_period(1717200000));
+    }
+
+    #[test]
+    fn admit_test() {
+        // 2024-05-15
+        let now = 1715731200;
+        let account = Arc::new(Account::new(&user_config("alice"), Arc::new(AtomicBool::new(false)), now).unwrap());
+
+        let lease = account.admit(now).unwrap();
+        assert_eq!(Some(AdmissionError::TooManyTunnels), account.admit(now).err());
+        drop(lease);
+        assert_eq!(1, account.admit(now).map(|lease| lease.account().active_tunnels()).unwrap());
+
+        assert!(!account.add_usage(99, now));
+        assert!(account.add_usage(1, now));
+        assert_eq!(Some(AdmissionError::QuotaExceeded), account.admit(now).err());
+
+        // 下个月流量重新计算，但账号已到期
+        let next_month = 1717200000;
+        assert_eq!(0, account.usage_bytes(next_month));
+        assert_eq!(Some(AdmissionError::Expired), account.admit(next_month).err());
+    }
+
+    #[test]
+    fn usage_file_test() {
+        let path = std::env::temp_dir().join(format!("goal_usage_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let now = 1715731200;
+        let auth_config = AuthConfiguration {
+            window_secs: 60,
+            nonce_capacity: 10,
+            nonce_journal: None,
+            usage_file: Some(path.clone()),
+            users: vec![user_config("alice"), user_config("bob")],
+        };
+
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        accounts.get("alice").unwrap().add_usage(42, now);
+        accounts.save_usage().unwrap();
+
+        // 模拟 goal 重启
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        assert_eq!(42, accounts.get("alice").unwrap().usage_bytes(now));
+        assert_eq!(0, accounts.get("bob").unwrap().usage_bytes(now));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn usage_file_retry_test() {
+        let dir = std::env::temp_dir().join(format!("goal_usage_retry_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("usage.txt");
+
+        let now = 1715731200;
+        let auth_config = AuthConfiguration {
+            window_secs: 60,
+            nonce_capacity: 10,
+            nonce_journal: None,
+            usage_file: Some(path.clone()),
+            users: vec![user_config("alice")],
+        };
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        accounts.get("alice").unwrap().add_usage(42, now);
+
+        // 目录不存在时写入失败，没有新的流量也会在下次重试
+        assert!(accounts.save_usage().is_err());
+        std::fs::create_dir_all(&dir).unwrap();
+        accounts.save_usage().unwrap();
+
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        assert_eq!(42, accounts.get("alice").unwrap().usage_bytes(now));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reload_test() {
+        let now = 1715731200;
+        let mut auth_config = AuthConfiguration {
+            window_secs: 60,
+            nonce_capacity: 10,
+            nonce_journal: None,
+            usage_file: None,
+            users: vec![user_config("alice")],
+        };
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        let lease = accounts.get("alice").unwrap().admit(now).unwrap();
+        accounts.get("alice").unwrap().add_usage(42, now);
+
+        // 放宽 alice 的隧道数上限，增加 bob
+        let mut alice = user_config("alice");
+        alice.max_tunnels = Some(2);
+        auth_config.users = vec![alice, user_config("bob")];
+        let reloaded = accounts.reload(&auth_config, now).unwrap();
+
+        let alice = reloaded.get("alice").unwrap();
+        assert_eq!(42, alice.usage_bytes(now));
+        assert_eq!(1, alice.active_tunnels());
+        let second_lease = alice.admit(now).unwrap();
+        assert_eq!(Some(AdmissionError::TooManyTunnels), alice.admit(now).err());
+
+        // 旧配置下建立的隧道结束后同样归还名额
+        drop(lease);
+        assert_eq!(1, alice.active_tunnels());
+        drop(second_lease);
+        assert!(reloaded.get("bob").is_some());
+    }
+
+    #[test]
+    fn reload_limiter_test() {
+        let now = 1715731200;
+        let alice = |download_rate| UserConfiguration {
+            upload_rate: Some(1000),
+            download_rate: Some(download_rate),
+            ..user_config("alice")
+        };
+        let mut auth_config = AuthConfiguration {
+            window_secs: 60,
+            nonce_capacity: 10,
+            nonce_journal: None,
+            usage_file: None,
+            users: vec![alice(1000)],
+        };
+        let accounts = Accounts::new(&auth_config, now).unwrap();
+        let old = accounts.get("alice").unwrap().clone();
+        // 用完令牌桶
+        old.upload_limiter().unwrap().consume(1000);
+
+        // 只修改下载的速率
+        auth_config.users = vec![alice(2000)];
+        let reloaded = accounts.reload(&auth_config, now).unwrap();
+        let alice = reloaded.get("alice").unwrap();
+
+        // 上传沿用原来的令牌桶，已经没有令牌
+        assert!(std::ptr::eq(old.upload_limiter().unwrap(), alice.upload_limiter().unwrap()));
+        assert!(!alice.upload_limiter().unwrap().consume(500).is_zero());
+        assert_eq!(2000, alice.download_limiter().unwrap().rate());
+        assert!(!std::ptr::eq(old.download_limiter().unwrap(), alice.download_limiter().unwrap()));
+    }
+}
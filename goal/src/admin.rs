@@ -0,0 +1,213 @@
+// 管理接口，只监听回环地址
+//
+//   GET /sessions            列出活动的会话
+//   DELETE /sessions/{id}    结束会话
+//   GET /dns                 查看各 DNS 服务器的缓存
+//   GET /bans                列出封禁的 IP
+//   DELETE /bans             解除所有的封禁
+//   DELETE /bans/{ip}        解除一个 IP 的封禁
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use bytes::BytesMut;
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpStream};
+use crate::ban::Bans;
+use crate::endpoint::Resolvers;
+use crate::fallback::write_response;
+use crate::http_head::{read_request_head, RequestHead};
+use crate::session::Sessions;
+
+pub struct Admin {
+    sessions: Arc<Sessions>,
+    resolvers: Resolvers,
+    bans: Arc<Bans>,
+}
+
+#[derive(Debug, Serialize)]
+struct DnsCache {
+    server_address: String,
+    entries: Vec<DnsCacheEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct DnsCacheEntry {
+    name: String,
+    #[serde(rename = "type")]
+    q_type: String,
+    // 查询中时为 `None`
+    addresses: Option<Vec<String>>,
+    ttl_secs: u64,
+}
+
+impl Admin {
+    pub fn new(sessions: Arc<Sessions>, resolvers: Resolvers, bans: Arc<Bans>) -> Self {
+        Self { sessions, resolvers, bans }
+    }
+
+    pub async fn bind(bind_address: SocketAddr) -> std::io::Result<TcpListener> {
+        if !bind_address.ip().is_loopback() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("admin address {} is not a loopback address", bind_address),
+            ));
+        }
+        TcpListener::bind(bind_address).await
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match transfer::tcp_accept::tcp_accept(&listener).await {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.process(stream).await {
+                    tracing::debug!("Admin request from {} failed, err: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn process(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut buf = BytesMut::with_capacity(1024);
+        let head = read_request_head(&mut stream, &mut buf).await?;
+        let (status, body) = self.handle(&head).await;
+        tracing::info!("Admin {} {} {}", head.method, head.target, status);
+        write_response(&mut stream, &head, status, "application/json", &body).await
+    }
+
+    async fn handle(&self, head: &RequestHead) -> (u16, Vec<u8>) {
+        let segments: Vec<&str> = head.path().trim_matches('/').split('/').collect();
+
+        match (head.method.as_str(), segments.as_slice()) {
+            ("GET", ["sessions"]) => json(200, &self.sessions.list()),
+            ("DELETE", ["sessions", id]) => {
+                match id.parse::<u64>() {
+                    Ok(id) if self.sessions.kill(id) => {
+                        tracing::info!("Killed session {} by admin", id);
+                        (204, Vec::new())
+                    }
+                    _ => json(404, &"session not found"),
+                }
+            }
+            ("GET", ["dns"]) => json(200, &self.dns_caches().await),
+            ("GET", ["bans"]) => json(200, &self.bans.list()),
+            ("DELETE", ["bans"]) => {
+                let count = self.bans.clear();
+                tracing::info!("Cleared {} bans by admin", count);
+                (204, Vec::new())
+            }
+            ("DELETE", ["bans", ip]) => {
+                match ip.parse::<IpAddr>() {
+                    Ok(ip) if self.bans.unban(ip) => {
+                        tracing::info!("Unbanned {} by admin", ip);
+                        (204, Vec::new())
+                    }
+                    _ => json(404, &"ban not found"),
+                }
+            }
+            (_, ["sessions"]) | (_, ["sessions", _]) | (_, ["dns"]) | (_, ["bans"]) | (_, ["bans", _]) => {
+                json(405, &"method not allowed")
+            }
+            _ => json(404, &"not found"),
+        }
+    }
+
+    async fn dns_caches(&self) -> Vec<DnsCache> {
+        let mut resolvers = self.resolvers.list();
+        resolvers.sort_by_key(|(server_address, _)| *server_address);
+
+        let mut caches = Vec::with_capacity(resolvers.len());
+        for (server_address, handle) in &resolvers {
+            let mut entries: Vec<DnsCacheEntry> = handle.dump().await
+                .into_iter()
+                .map(|entry| DnsCacheEntry {
+                    name: entry.key.name,
+                    q_type: entry.key.q_type.to_string(),
+                    addresses: entry.result.map(|addrs| addrs.unwrap_or_default().iter().map(|a| a.to_string()).collect()),
+                    ttl_secs: entry.ttl.as_secs(),
+                })
+                .collect();
+            entries.sort_by(|a, b| (&a.name, &a.q_type).cmp(&(&b.name, &b.q_type)));
+
+            caches.push(DnsCache {
+                server_address: server_address.to_string(),
+                entries,
+            });
+        }
+
+        caches
+    }
+}
+
+fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> (u16, Vec<u8>) {
+    match serde_json::to_vec_pretty(value) {
+        Ok(body) => (status, body),
+        Err(_) => (500, Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_head::parse_request_head;
+
+    fn request(method: &str, target: &str) -> RequestHead {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", method, target);
+        parse_request_head(&BytesMut::from(raw.as_str())).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn handle_test() {
+        let sessions = Arc::new(Sessions::new());
+        let bans = Arc::new(Bans::new(Some(&configuration::BanConfiguration {
+            burst: 1,
+            refill_per_min: 0,
+            ban_secs: 60,
+            max_ban_secs: 60,
+            banned: Vec::new(),
+            exempt: Vec::new(),
+        })));
+        let admin = Admin::new(sessions.clone(), Resolvers::new(), bans.clone());
+        let session = sessions.register("127.0.0.1:50000".parse().unwrap(), None, "/goal".to_string());
+
+        let (status, body) = admin.handle(&request("GET", "/sessions")).await;
+        assert_eq!(200, status);
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session.id, list[0]["id"].as_u64().unwrap());
+        assert_eq!("127.0.0.1:50000", list[0]["client_addr"]);
+
+        assert_eq!(404, admin.handle(&request("DELETE", "/sessions/abc")).await.0);
+        assert_eq!(405, admin.handle(&request("POST", "/sessions")).await.0);
+        assert_eq!(404, admin.handle(&request("GET", "/")).await.0);
+
+        let target = format!("/sessions/{}", session.id);
+        assert_eq!(204, admin.handle(&request("DELETE", &target)).await.0);
+        session.killed().await;
+
+        let (status, body) = admin.handle(&request("GET", "/dns")).await;
+        assert_eq!(200, status);
+        assert_eq!(b"[]", body.as_slice());
+
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        bans.record_failure(ip);
+        bans.record_failure(ip);
+        let (status, body) = admin.handle(&request("GET", "/bans")).await;
+        assert_eq!(200, status);
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("192.0.2.1", list[0]["ip"]);
+        assert_eq!(204, admin.handle(&request("DELETE", "/bans/192.0.2.1")).await.0);
+        assert_eq!(404, admin.handle(&request("DELETE", "/bans/192.0.2.1")).await.0);
+        assert_eq!(204, admin.handle(&request("DELETE", "/bans")).await.0);
+        assert_eq!(405, admin.handle(&request("POST", "/bans")).await.0);
+    }
+
+    #[tokio::test]
+    async fn bind_loopback_only_test() {
+        assert!(Admin::bind("0.0.0.0:0".parse().unwrap()).await.is_err());
+        assert!(Admin::bind("127.0.0.1:0".parse().unwrap()).await.is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use auth_token::{Token, TokenError};
+use configuration::AuthConfiguration;
+use crate::replay::{ReplayError, ReplayGuard};
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(TokenError),
+    UnknownUser(String),
+    Replay(ReplayError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "missing token"),
+            AuthError::InvalidToken(e) => write!(f, "{}", e),
+            AuthError::UnknownUser(user) => write!(f, "unknown user '{}'", user),
+            AuthError::Replay(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// 校验 soccer 在握手请求中携带的令牌
+pub struct Authenticator {
+    secrets: HashMap<String, Vec<u8>>,
+    // 重新加载配置时沿用，已使用的 nonce 不会被遗忘
+    replay_guard: Arc<Mutex<ReplayGuard>>,
+}
+
+impl Authenticator {
+    pub fn new(auth_config: &AuthConfiguration) -> std::io::Result<Self> {
+        let secrets = secrets(auth_config);
+
+        let replay_guard = match &auth_config.nonce_journal {
+            Some(path) => ReplayGuard::with_journal(
+                auth_config.window_secs,
+                auth_config.nonce_capacity,
+                path,
+                auth_token::unix_timestamp(),
+            )?,
+            None => ReplayGuard::new(auth_config.window_secs, auth_config.nonce_capacity),
+        };
+
+        Ok(Self {
+            secrets,
+            replay_guard: Arc::new(Mutex::new(replay_guard)),
+        })
+    }
+
+    // 按新的配置更新用户与密钥；nonce 相关的配置需要重启后才生效
+    pub fn reload(&self, auth_config: &AuthConfiguration) -> Self {
+        Self {
+            secrets: secrets(auth_config),
+            replay_guard: self.replay_guard.clone(),
+        }
+    }
+
+    // 认证成功时返回用户名
+    pub fn authenticate(&self, header_value: Option<&str>) -> Result<String, AuthError> {
+        let header_value = header_value.ok_or(AuthError::MissingToken)?;
+        let token = Token::parse(header_value).map_err(AuthError::InvalidToken)?;
+
+        let secret = self.secrets.get(&token.user)
+            .ok_or_else(|| AuthError::UnknownUser(token.user.clone()))?;
+        token.verify(secret).map_err(AuthError::InvalidToken)?;
+
+        // 签名正确之后才记录 nonce，避免伪造的令牌占满 nonce 集合
+        let mut replay_guard = self.replay_guard.lock().unwrap();
+        replay_guard
+            .check(&token.user, &token.nonce, token.timestamp, auth_token::unix_timestamp())
+            .map_err(AuthError::Replay)?;
+
+        Ok(token.user)
+    }
+}
+
+fn secrets(auth_config: &AuthConfiguration) -> HashMap<String, Vec<u8>> {
+    auth_config.users.iter()
+        .map(|u| (u.name.clone(), u.secret.as_bytes().to_vec()))
+        .collect()
+}
@@ -0,0 +1,342 @@
+// 握手失败（错误的路径、认证失败、无效的请求）的来源 IP 封禁列表
+//
+// 每个来源 IP 一个令牌桶，每次失败消耗一个令牌，令牌耗尽时封禁；
+// 封禁期间的连接在 accept 之后直接关闭，不再读取请求。
+// 同一 IP 再次被封禁时时长加倍，直到 `max_ban_secs`；`max_ban_secs` 内没有再失败时忘记该 IP，
+// 从未被封禁过的 IP 在令牌恢复满之后就忘记。IPv6 按 /64 记录，记录的数量不超过 `MAX_ENTRIES`
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use ipnet::IpNet;
+use serde::Serialize;
+use configuration::BanConfiguration;
+
+// 清理过期记录的间隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// 记录的上限，达到上限时先清理，仍然没有空间时不再记录新的 IP
+const MAX_ENTRIES: usize = 100_000;
+
+struct Entry {
+    tokens: f64,
+    last: Instant,
+    // 被封禁的次数
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+struct Entries {
+    entries: HashMap<IpAddr, Entry>,
+    last_prune: Instant,
+    max_entries: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BanInfo {
+    pub ip: String,
+    // 配置中的封禁为 `None`
+    pub remaining_secs: Option<u64>,
+    pub strikes: u32,
+}
+
+pub struct Bans {
+    // 重新加载配置时只替换 `config`，已有的封禁保持不变。未配置时不封禁
+    config: Mutex<Option<BanConfiguration>>,
+    entries: Mutex<Entries>,
+}
+
+impl Bans {
+    pub fn new(ban_config: Option<&BanConfiguration>) -> Self {
+        Self {
+            config: Mutex::new(ban_config.cloned()),
+            entries: Mutex::new(Entries {
+                entries: HashMap::new(),
+                last_prune: Instant::now(),
+                max_entries: MAX_ENTRIES,
+            }),
+        }
+    }
+
+    pub fn reconfigure(&self, ban_config: Option<&BanConfiguration>) {
+        *self.config.lock().unwrap() = ban_config.cloned();
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let config = self.config.lock().unwrap();
+        let config = match config.as_ref() {
+            Some(config) => config,
+            None => return false,
+        };
+        if contains(&config.exempt, ip) {
+            return false;
+        }
+        if contains(&config.banned, ip) {
+            return true;
+        }
+
+        self.entries.lock().unwrap().entries.get(&key_of(ip))
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|banned_until| now < banned_until)
+    }
+
+    // 记录一次握手失败，新封禁时返回封禁的时长。
+    // 回环地址不封禁，如经 Unix 套接字或本机反向代理的连接
+    pub fn record_failure(&self, ip: IpAddr) -> Option<Duration> {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let config = self.config.lock().unwrap().clone()?;
+        if ip.is_loopback() || contains(&config.exempt, ip) {
+            return None;
+        }
+
+        let burst = config.burst.max(1) as f64;
+        let rate = config.refill_per_min as f64 / 60.0;
+        let max_ban = Duration::from_secs(config.max_ban_secs);
+
+        let key = key_of(ip);
+        let mut entries = self.entries.lock().unwrap();
+        let full = entries.entries.len() >= entries.max_entries && !entries.entries.contains_key(&key);
+        if full || now.saturating_duration_since(entries.last_prune) >= PRUNE_INTERVAL {
+            entries.last_prune = now;
+            entries.entries.retain(|_, entry| {
+                if entry.banned_until.is_some_and(|banned_until| now < banned_until) {
+                    return true;
+                }
+                // 从未被封禁过且令牌已恢复满
+                let elapsed = now.saturating_duration_since(entry.last).as_secs_f64();
+                if entry.strikes == 0 && entry.tokens + elapsed * rate >= burst {
+                    return false;
+                }
+                now.saturating_duration_since(entry.last) < max_ban
+            });
+            if entries.entries.len() >= entries.max_entries {
+                return None;
+            }
+        }
+
+        let entry = entries.entries.entry(key).or_insert(Entry {
+            tokens: burst,
+            last: now,
+            strikes: 0,
+            banned_until: None,
+        });
+        if entry.banned_until.is_some_and(|banned_until| now < banned_until) {
+            return None;
+        }
+
+        let elapsed = now.saturating_duration_since(entry.last).as_secs_f64();
+        entry.tokens = (entry.tokens + elapsed * rate).min(burst);
+        entry.last = now;
+
+        entry.tokens -= 1.0;
+        if entry.tokens >= 0.0 {
+            return None;
+        }
+
+        // 1、2、4……倍的 ban_secs，不超过 max_ban_secs
+        let factor = 1u64 << entry.strikes.min(32);
+        let ban = Duration::from_secs(config.ban_secs.saturating_mul(factor)).min(max_ban);
+        entry.strikes += 1;
+        entry.banned_until = Some(now + ban);
+        // 解封后重新开始计数
+        entry.tokens = burst;
+        Some(ban)
+    }
+
+    // 配置中的封禁与尚未到期的封禁
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        let mut list: Vec<BanInfo> = match self.config.lock().unwrap().as_ref() {
+            Some(config) => config.banned.iter()
+                .map(|net| BanInfo { ip: net.to_string(), remaining_secs: None, strikes: 0 })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let entries = self.entries.lock().unwrap();
+        let mut banned: Vec<BanInfo> = entries.entries.iter()
+            .filter_map(|(ip, entry)| {
+                let remaining = entry.banned_until?.checked_duration_since(now)?;
+                Some(BanInfo {
+                    ip: display_key(ip),
+                    remaining_secs: Some(remaining.as_secs()),
+                    strikes: entry.strikes,
+                })
+            })
+            .collect();
+        banned.sort_by(|a, b| a.ip.cmp(&b.ip));
+        list.extend(banned);
+        list
+    }
+
+    // 解除一个 IP 的封禁并忘记其失败记录，配置中的封禁需要修改配置
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.entries.lock().unwrap().entries.remove(&key_of(ip))
+            .is_some_and(|entry| entry.banned_until.is_some_and(|banned_until| Instant::now() < banned_until))
+    }
+
+    // 解除所有的封禁，返回解除的数量
+    pub fn clear(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.entries.values()
+            .filter(|entry| entry.banned_until.is_some_and(|banned_until| now < banned_until))
+            .count();
+        entries.entries.clear();
+        count
+    }
+}
+
+// 同一个 /64 通常属于同一个用户，按单个 IPv6 地址记录时轮换地址就能绕过封禁
+fn key_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !((1u128 << 64) - 1);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+fn display_key(key: &IpAddr) -> String {
+    match key {
+        IpAddr::V4(_) => key.to_string(),
+        IpAddr::V6(_) => format!("{}/64", key),
+    }
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban_config() -> BanConfiguration {
+        BanConfiguration {
+            burst: 2,
+            refill_per_min: 60,
+            ban_secs: 10,
+            max_ban_secs: 25,
+            banned: vec!["198.51.100.0/24".parse().unwrap()],
+            exempt: vec!["192.0.2.100/32".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn record_failure_test() {
+        let bans = Bans::new(Some(&ban_config()));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(None, bans.record_failure_at(ip, now));
+        assert_eq!(None, bans.record_failure_at(ip, now));
+        // 每秒恢复一个令牌
+        assert_eq!(None, bans.record_failure_at(ip, now + Duration::from_secs(1)));
+        assert_eq!(Some(Duration::from_secs(10)), bans.record_failure_at(ip, now + Duration::from_secs(1)));
+        assert!(bans.is_banned_at(ip, now + Duration::from_secs(10)));
+        assert!(!bans.is_banned_at(ip, now + Duration::from_secs(11)));
+
+        // 再次封禁时加倍，不超过 max_ban_secs
+        let later = now + Duration::from_secs(11);
+        bans.record_failure_at(ip, later);
+        bans.record_failure_at(ip, later);
+        assert_eq!(Some(Duration::from_secs(20)), bans.record_failure_at(ip, later));
+        let later = later + Duration::from_secs(20);
+        bans.record_failure_at(ip, later);
+        bans.record_failure_at(ip, later);
+        assert_eq!(Some(Duration::from_secs(25)), bans.record_failure_at(ip, later));
+
+        let exempt: IpAddr = "192.0.2.100".parse().unwrap();
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..10 {
+            assert_eq!(None, bans.record_failure_at(exempt, now));
+            assert_eq!(None, bans.record_failure_at(loopback, now));
+        }
+
+        // 未配置时不封禁
+        bans.reconfigure(None);
+        assert!(!bans.is_banned_at(ip, later));
+        assert_eq!(None, bans.record_failure("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn list_test() {
+        let bans = Bans::new(Some(&ban_config()));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            bans.record_failure(ip);
+        }
+        assert!(bans.is_banned(ip));
+        assert!(bans.is_banned("198.51.100.7".parse().unwrap()));
+
+        let list = bans.list();
+        assert_eq!("198.51.100.0/24", list[0].ip);
+        assert_eq!(None, list[0].remaining_secs);
+        assert_eq!("192.0.2.1", list[1].ip);
+        assert_eq!(1, list[1].strikes);
+
+        assert!(bans.unban(ip));
+        assert!(!bans.is_banned(ip));
+        assert!(!bans.unban(ip));
+
+        for _ in 0..3 {
+            bans.record_failure(ip);
+        }
+        assert_eq!(1, bans.clear());
+        assert!(!bans.is_banned(ip));
+    }
+
+    #[test]
+    fn ipv6_prefix_test() {
+        let bans = Bans::new(Some(&ban_config()));
+        let now = Instant::now();
+        // 同一个 /64 中轮换地址
+        for i in 1..=3 {
+            bans.record_failure_at(format!("2001:db8::{}", i).parse().unwrap(), now);
+        }
+        assert!(bans.is_banned_at("2001:db8::ffff".parse().unwrap(), now));
+        assert!(!bans.is_banned_at("2001:db8:0:1::1".parse().unwrap(), now));
+        assert_eq!("2001:db8::/64", bans.list()[1].ip);
+        assert!(bans.unban("2001:db8::1234".parse().unwrap()));
+    }
+
+    #[test]
+    fn prune_test() {
+        let bans = Bans::new(Some(&ban_config()));
+        bans.entries.lock().unwrap().max_entries = 2;
+        let now = Instant::now();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let banned: IpAddr = "192.0.2.2".parse().unwrap();
+        bans.record_failure_at(first, now);
+        for _ in 0..3 {
+            bans.record_failure_at(banned, now);
+        }
+
+        // 已满且都不能清理时不再记录新的 IP
+        let third: IpAddr = "192.0.2.3".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(None, bans.record_failure_at(third, now));
+        }
+        assert!(!bans.is_banned_at(third, now));
+
+        // 令牌恢复满的记录被清理，腾出空间
+        let later = now + Duration::from_secs(2);
+        for _ in 0..2 {
+            bans.record_failure_at(third, later);
+        }
+        assert_eq!(Some(Duration::from_secs(10)), bans.record_failure_at(third, later));
+        assert!(bans.is_banned_at(banned, later));
+        let entries = bans.entries.lock().unwrap();
+        assert!(!entries.entries.contains_key(&first));
+        assert_eq!(2, entries.entries.len());
+    }
+}
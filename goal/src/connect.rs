@@ -0,0 +1,407 @@
+// 连接目标，参考 RFC 8305 Happy Eyeballs：
+// https://datatracker.ietf.org/doc/html/rfc8305
+//
+// 同时查询 AAAA 与 A 记录，IPv6 与 IPv4 地址交替排列，依次间隔一段时间发起连接，
+// 先成功的连接胜出；前一个连接失败时立即发起下一个。
+//
+// 按规则选中上游代理时，经上游代理连接目标，不再竞速
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use configuration::GoalConfiguration;
+use domain_name_actor::actor::{ActorHandle, QueryError, QueryResult};
+use domain_name_query_types::NameQuery;
+use crate::egress::EgressDenied;
+use crate::endpoint::Endpoint;
+use crate::outbound::OutboundPolicy;
+use protocol::{ConnectReply, DnsFamily};
+use crate::upstream::{UpstreamError, Upstream, Upstreams};
+
+#[derive(Debug)]
+pub enum ConnectError {
+    // 没有解析到地址
+    Unresolved,
+    // DNS 服务器在期限内没有应答
+    DnsTimedOut,
+    // 解析到的地址都被出站策略拒绝
+    Denied(EgressDenied),
+    TimedOut,
+    // 所有连接尝试都失败，保留最后一个错误
+    Failed(std::io::Error),
+    // 经上游代理连接失败，保留最后一个错误
+    Upstream(UpstreamError),
+}
+
+impl ConnectError {
+    // 用作指标的标签
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ConnectError::Unresolved => "unresolved",
+            ConnectError::DnsTimedOut => "dns_timeout",
+            ConnectError::Denied(_) => "denied",
+            ConnectError::TimedOut => "connect_timeout",
+            ConnectError::Failed(_) => "connect_failed",
+            ConnectError::Upstream(_) => "upstream_failed",
+        }
+    }
+
+    pub fn reply(&self) -> ConnectReply {
+        match self {
+            ConnectError::Unresolved | ConnectError::DnsTimedOut => ConnectReply::HostUnreachable,
+            ConnectError::Denied(_) => ConnectReply::NotAllowed,
+            ConnectError::TimedOut => ConnectReply::TtlExpired,
+            ConnectError::Failed(e) => ConnectReply::from_io_error(e),
+            ConnectError::Upstream(e) => e.reply(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Unresolved => write!(f, "could not resolve host"),
+            ConnectError::DnsTimedOut => write!(f, "DNS query timed out"),
+            ConnectError::Denied(e) => write!(f, "denied by egress policy, {}", e),
+            ConnectError::TimedOut => write!(f, "connect timed out"),
+            ConnectError::Failed(e) => write!(f, "connect failed, {}", e),
+            ConnectError::Upstream(e) => write!(f, "upstream failed, {}", e),
+        }
+    }
+}
+
+// 已连接的目标
+pub struct Connected {
+    pub stream: TcpStream,
+    // 对端的地址，经上游代理时是代理的地址
+    pub peer_addr: SocketAddr,
+    // 目标的地址，由上游代理解析域名时未知
+    pub dest_addr: Option<SocketAddr>,
+    // 上游代理的名称
+    pub upstream: Option<String>,
+}
+
+impl fmt::Display for Connected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.upstream {
+            Some(upstream) => write!(f, "via '{}' {}", upstream, self.peer_addr),
+            None => write!(f, "{}", self.peer_addr),
+        }
+    }
+}
+
+pub struct Connector {
+    timeout: Duration,
+    attempt_delay: Duration,
+    resolution_delay: Duration,
+    // 全局的出口
+    outbound: OutboundPolicy,
+    // 单独配置了出口的用户
+    user_outbounds: HashMap<String, OutboundPolicy>,
+    upstreams: Upstreams,
+}
+
+impl Connector {
+    pub fn new(goal_config: &GoalConfiguration) -> Result<Self, String> {
+        let connect_config = &goal_config.connect;
+
+        let mut user_outbounds = HashMap::new();
+        let users = goal_config.auth.iter().flat_map(|auth_config| auth_config.users.iter());
+        for user_config in users {
+            if let Some(outbound_config) = &user_config.outbound {
+                let outbound = OutboundPolicy::new(outbound_config)
+                    .map_err(|e| format!("user '{}': {}", user_config.name, e))?;
+                user_outbounds.insert(user_config.name.clone(), outbound);
+            }
+        }
+
+        Ok(Self {
+            timeout: Duration::from_millis(connect_config.timeout_ms),
+            attempt_delay: Duration::from_millis(connect_config.attempt_delay_ms),
+            resolution_delay: Duration::from_millis(connect_config.resolution_delay_ms),
+            outbound: OutboundPolicy::new(&goal_config.outbound)?,
+            user_outbounds,
+            upstreams: Upstreams::new(goal_config)?,
+        })
+    }
+
+    // 出口的优先级：用户 > 路径 > 全局
+    fn outbound<'a>(&'a self, endpoint: &'a Endpoint, user: Option<&str>) -> &'a OutboundPolicy {
+        user.and_then(|user| self.user_outbounds.get(user))
+            .or(endpoint.outbound.as_ref())
+            .unwrap_or(&self.outbound)
+    }
+
+    // 解析域名并连接；未认证时 `user` 为 `None`。
+    // `family` 只用于本地解析，由上游代理解析时不起作用
+    pub async fn connect(
+        &self,
+        endpoint: &Endpoint,
+        user: Option<&str>,
+        domain: &str,
+        port: u16,
+        family: DnsFamily,
+    ) -> Result<Connected, ConnectError> {
+        let outbound = self.outbound(endpoint, user);
+        let f = async {
+            match self.upstreams.select(&endpoint.path, user, domain, port) {
+                Some(upstream) => self.connect_via(upstream, outbound, endpoint, domain, port, family).await,
+                None => {
+                    let candidates = self.candidates(endpoint, domain, port, family).await?;
+                    let (stream, peer_addr) = race(candidates, self.attempt_delay, outbound).await?;
+                    Ok(Connected { stream, peer_addr, dest_addr: Some(peer_addr), upstream: None })
+                }
+            }
+        };
+
+        match tokio::time::timeout(self.timeout, f).await {
+            Ok(ret) => ret,
+            Err(_) => Err(ConnectError::TimedOut),
+        }
+    }
+
+    // 解析域名，返回出站策略允许的地址，按 Happy Eyeballs 的顺序排列
+    async fn candidates(&self, endpoint: &Endpoint, domain: &str, port: u16, family: DnsFamily) -> Result<Vec<SocketAddr>, ConnectError> {
+        let ip_addrs = resolve(endpoint, domain, family, self.resolution_delay).await?;
+
+        // 检查的是解析后的地址，防止借助域名访问内网
+        let mut denied = None;
+        let candidates: Vec<SocketAddr> = ip_addrs.into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .filter(|addr| match endpoint.egress_policy.check(*addr) {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::debug!("Egress to {} ({}) denied, reason: {}", addr, domain, e);
+                    denied.get_or_insert(e);
+                    false
+                }
+            })
+            .collect();
+        if let (true, Some(e)) = (candidates.is_empty(), denied) {
+            return Err(ConnectError::Denied(e));
+        }
+
+        Ok(sort_candidates(candidates))
+    }
+
+    // 由上游代理解析域名时直接把域名交给它，否则在本地解析后依次尝试各个地址
+    async fn connect_via(
+        &self,
+        upstream: &Upstream,
+        outbound: &OutboundPolicy,
+        endpoint: &Endpoint,
+        domain: &str,
+        port: u16,
+        family: DnsFamily,
+    ) -> Result<Connected, ConnectError> {
+        // 目标及其地址
+        let hosts: Vec<(String, Option<SocketAddr>)> = if upstream.remote_dns && domain.parse::<IpAddr>().is_err() {
+            endpoint.egress_policy.check_port(port).map_err(ConnectError::Denied)?;
+            vec![(domain.to_string(), None)]
+        } else {
+            self.candidates(endpoint, domain, port, family).await?
+                .into_iter()
+                .map(|addr| (addr.ip().to_string(), Some(addr)))
+                .collect()
+        };
+
+        let mut last_err = None;
+        for (host, dest_addr) in hosts {
+            let ret = async {
+                let mut stream = outbound.connect(upstream.addr).await?;
+                upstream.handshake(&mut stream, &host, port).await?;
+                Ok::<_, UpstreamError>(stream)
+            }.await;
+
+            match ret {
+                Ok(stream) => {
+                    return Ok(Connected {
+                        stream,
+                        peer_addr: upstream.addr,
+                        dest_addr,
+                        upstream: Some(upstream.name.clone()),
+                    });
+                }
+                Err(e) => {
+                    tracing::debug!("Connect to {}:{} via '{}' failed, err: {}", host, port, upstream.name, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.map_or(ConnectError::Unresolved, ConnectError::Upstream))
+    }
+}
+
+// IP 地址形式的目标无需解析；指定了地址族时只查询一种记录。
+// 没有得到任何地址时，有查询超时则为 `DnsTimedOut`，否则为 `Unresolved`
+async fn resolve(endpoint: &Endpoint, domain: &str, family: DnsFamily, resolution_delay: Duration) -> Result<Vec<IpAddr>, ConnectError> {
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    let handle = &endpoint.domain_name_handle;
+    let results = match family {
+        DnsFamily::Ipv4 => vec![handle.query_all(NameQuery::a_record(domain)).await],
+        DnsFamily::Ipv6 => vec![handle.query_all(NameQuery::aaaa_record(domain)).await],
+        DnsFamily::Any => resolve_dual_stack(handle, domain, resolution_delay).await,
+    };
+
+    let timed_out = results.contains(&Err(QueryError::TimedOut));
+    let addrs: Vec<IpAddr> = results.into_iter().flat_map(Result::unwrap_or_default).collect();
+    match (addrs.is_empty(), timed_out) {
+        (false, _) => Ok(addrs),
+        (true, true) => Err(ConnectError::DnsTimedOut),
+        (true, false) => {
+            domain_name_actor::actor::record_no_answer();
+            Err(ConnectError::Unresolved)
+        }
+    }
+}
+
+// 返回 AAAA 与 A 记录的查询结果，IPv6 在前
+async fn resolve_dual_stack(handle: &ActorHandle, domain: &str, resolution_delay: Duration) -> Vec<QueryResult> {
+    let aaaa = handle.query_all(NameQuery::aaaa_record(domain));
+    let a = handle.query_all(NameQuery::a_record(domain));
+    tokio::pin!(aaaa);
+    tokio::pin!(a);
+
+    // https://datatracker.ietf.org/doc/html/rfc8305#section-3
+    // A 记录先返回时，再稍等 AAAA 记录，以便优先使用 IPv6
+    let (v6, v4) = tokio::select! {
+        v6 = &mut aaaa => (v6, a.await),
+        v4 = &mut a => {
+            let v6 = tokio::time::timeout(resolution_delay, aaaa).await.unwrap_or(Ok(Vec::new()));
+            (v6, v4)
+        }
+    };
+
+    vec![v6, v4]
+}
+
+// https://datatracker.ietf.org/doc/html/rfc8305#section-4
+// IPv6 与 IPv4 地址交替排列，以第一个地址的地址族开始
+pub fn sort_candidates(candidates: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match candidates.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return candidates,
+    };
+
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = candidates.into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (p, o) => {
+                sorted.extend(p);
+                sorted.extend(o);
+            }
+        }
+    }
+    sorted
+}
+
+// 依次间隔 `attempt_delay` 发起连接，先成功的胜出，其余的连接尝试被丢弃
+pub async fn race(
+    candidates: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    outbound: &OutboundPolicy,
+) -> Result<(TcpStream, SocketAddr), ConnectError> {
+    let mut remaining = candidates.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let start_attempt = |addr: SocketAddr| async move {
+        (addr, outbound.connect(addr).await)
+    };
+
+    match remaining.next() {
+        Some(addr) => attempts.push(start_attempt(addr)),
+        None => return Err(ConnectError::Unresolved),
+    }
+
+    let delay = tokio::time::sleep(attempt_delay);
+    tokio::pin!(delay);
+    let mut next = remaining.next();
+
+    loop {
+        tokio::select! {
+            _ = &mut delay, if next.is_some() => {
+                if let Some(addr) = next.take() {
+                    attempts.push(start_attempt(addr));
+                }
+                next = remaining.next();
+                delay.as_mut().reset(tokio::time::Instant::now() + attempt_delay);
+            }
+            Some((addr, ret)) = attempts.next() => {
+                match ret {
+                    Ok(stream) => return Ok((stream, addr)),
+                    Err(e) => {
+                        tracing::debug!("Connect to {} failed, err: {}", addr, e);
+
+                        // 失败时立即发起下一个连接尝试；全部失败时返回最后一个错误
+                        if let Some(addr) = next.take() {
+                            attempts.push(start_attempt(addr));
+                            next = remaining.next();
+                            delay.as_mut().reset(tokio::time::Instant::now() + attempt_delay);
+                        } else if attempts.is_empty() {
+                            return Err(ConnectError::Failed(e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn sort_candidates_test() {
+        let sorted = sort_candidates(addrs(&["[::1]:80", "[::2]:80", "[::3]:80", "1.1.1.1:80", "2.2.2.2:80"]));
+        assert_eq!(addrs(&["[::1]:80", "1.1.1.1:80", "[::2]:80", "2.2.2.2:80", "[::3]:80"]), sorted);
+
+        let sorted = sort_candidates(addrs(&["1.1.1.1:80", "[::1]:80"]));
+        assert_eq!(addrs(&["1.1.1.1:80", "[::1]:80"]), sorted);
+    }
+
+    // 没有监听的端口，连接会被拒绝
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn race_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = listener.local_addr().unwrap();
+
+        let candidates = vec![closed_addr().await, closed_addr().await, open_addr];
+        let (_stream, addr) = race(candidates, Duration::from_secs(10), &OutboundPolicy::default()).await.unwrap();
+        assert_eq!(open_addr, addr);
+    }
+
+    #[tokio::test]
+    async fn race_failed_test() {
+        let candidates = vec![closed_addr().await, closed_addr().await];
+        let ret = race(candidates, Duration::from_millis(10), &OutboundPolicy::default()).await;
+
+        match ret {
+            Err(e @ ConnectError::Failed(_)) => assert_eq!(ConnectReply::ConnectionRefused, e.reply()),
+            other => panic!("unexpected result: {:?}", other.map(|(_, addr)| addr)),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use ipnet::IpNet;
+use configuration::{EgressConfiguration, PortRange};
+
+// 不应从 goal 访问的地址段。NAT64、6to4 与 IPv4 兼容地址先由 `canonical_ip` 转为其中的 IPv4 地址再检查
+// https://www.iana.org/assignments/iana-ipv4-special-registry/iana-ipv4-special-registry.xhtml
+// https://www.iana.org/assignments/iana-ipv6-special-registry/iana-ipv6-special-registry.xhtml
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b:1::/48",
+    "fc00::/7",
+    "fe80::/10",
+    "fec0::/10",
+    "ff00::/8",
+];
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum EgressDenied {
+    DeniedAddress,
+    DeniedPort,
+    PortNotAllowed,
+    AddressNotAllowed,
+    PrivateAddress,
+}
+
+impl fmt::Display for EgressDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            EgressDenied::DeniedAddress => "address is denied",
+            EgressDenied::DeniedPort => "port is denied",
+            EgressDenied::PortNotAllowed => "port is not allowed",
+            EgressDenied::AddressNotAllowed => "address is not allowed",
+            EgressDenied::PrivateAddress => "private address is denied",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct EgressPolicy {
+    private_ranges: Vec<IpNet>,
+    deny_private: bool,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    allow_ports: Vec<PortRange>,
+    deny_ports: Vec<PortRange>,
+}
+
+impl EgressPolicy {
+    pub fn new(egress_config: &EgressConfiguration) -> Self {
+        let private_ranges = PRIVATE_RANGES.iter()
+            .map(|s| s.parse::<IpNet>().unwrap())
+            .collect();
+
+        Self {
+            private_ranges,
+            deny_private: egress_config.deny_private,
+            allow: egress_config.allow.clone(),
+            deny: egress_config.deny.clone(),
+            allow_ports: egress_config.allow_ports.clone(),
+            deny_ports: egress_config.deny_ports.clone(),
+        }
+    }
+
+    pub fn check(&self, dest_addr: SocketAddr) -> Result<(), EgressDenied> {
+        let ip = canonical_ip(dest_addr.ip());
+        let port = dest_addr.port();
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return Err(EgressDenied::DeniedAddress);
+        }
+        self.check_port(port)?;
+
+        if self.allow.iter().any(|net| net.contains(&ip)) {
+            return Ok(());
+        }
+        if !self.allow.is_empty() {
+            return Err(EgressDenied::AddressNotAllowed);
+        }
+
+        if self.deny_private && self.private_ranges.iter().any(|net| net.contains(&ip)) {
+            return Err(EgressDenied::PrivateAddress);
+        }
+
+        Ok(())
+    }
+
+    // 目标的 IP 未知时（如由上游代理解析域名）只检查端口
+    pub fn check_port(&self, port: u16) -> Result<(), EgressDenied> {
+        if self.deny_ports.iter().any(|range| range.contains(port)) {
+            return Err(EgressDenied::DeniedPort);
+        }
+        if !self.allow_ports.is_empty() && !self.allow_ports.iter().any(|range| range.contains(port)) {
+            return Err(EgressDenied::PortNotAllowed);
+        }
+        Ok(())
+    }
+}
+
+// 含有 IPv4 地址的 IPv6 地址按其中的 IPv4 地址检查，经 NAT64 或 6to4 网关同样会到达该地址：
+// "::ffff:127.0.0.1"（IPv4 映射）、"::127.0.0.1"（IPv4 兼容）、"64:ff9b::127.0.0.1"（NAT64）、
+// "2002:7f00:1::"（6to4）与 "127.0.0.1" 是同一个地址
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    let v6 = match ip {
+        IpAddr::V6(v6) => v6,
+        IpAddr::V4(_) => return ip,
+    };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return IpAddr::V4(v4);
+    }
+
+    let segments = v6.segments();
+    let embedded = match segments {
+        // "::" 与 "::1" 不是 IPv4 兼容地址
+        [0, 0, 0, 0, 0, 0, 0, 0] | [0, 0, 0, 0, 0, 0, 0, 1] => None,
+        [0, 0, 0, 0, 0, 0, high, low] => Some((high, low)),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some((high, low)),
+        [0x2002, high, low, ..] => Some((high, low)),
+        _ => None,
+    };
+    match embedded {
+        Some((high, low)) => IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        None => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn check(policy: &EgressPolicy, addr: &str) -> Result<(), EgressDenied> {
+        policy.check(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn default_policy_test() {
+        let policy = EgressPolicy::new(&EgressConfiguration::default());
+
+        assert_eq!(Ok(()), check(&policy, "1.1.1.1:443"));
+        assert_eq!(Ok(()), check(&policy, "[2606:4700:4700::1111]:443"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "127.0.0.1:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "10.1.2.3:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "169.254.169.254:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[::1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[::ffff:127.0.0.1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[fe80::1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[fec0::1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[64:ff9b:1::1]:80"));
+    }
+
+    #[test]
+    fn embedded_ipv4_test() {
+        let policy = EgressPolicy::new(&EgressConfiguration::default());
+
+        // NAT64、6to4 与 IPv4 兼容地址
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[64:ff9b::7f00:1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[64:ff9b::10.1.2.3]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[2002:7f00:1::]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[2002:a9fe:a9fe:1::1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[::127.0.0.1]:80"));
+        assert_eq!(Err(EgressDenied::PrivateAddress), check(&policy, "[::c0a8:101]:80"));
+        // 其中的公网地址可以访问
+        assert_eq!(Ok(()), check(&policy, "[64:ff9b::1.1.1.1]:443"));
+        assert_eq!(Ok(()), check(&policy, "[2002:101:101::]:443"));
+
+        // 同样适用于 allow 与 deny
+        let egress_config = EgressConfiguration {
+            deny: vec!["1.1.1.0/24".parse().unwrap()],
+            ..EgressConfiguration::default()
+        };
+        let policy = EgressPolicy::new(&egress_config);
+        assert_eq!(Err(EgressDenied::DeniedAddress), check(&policy, "[64:ff9b::1.1.1.1]:443"));
+        assert_eq!(Err(EgressDenied::DeniedAddress), check(&policy, "[2002:101:101::]:443"));
+    }
+
+    #[test]
+    fn rules_test() {
+        let egress_config = EgressConfiguration {
+            deny_private: true,
+            allow: vec!["10.0.0.0/24".parse().unwrap(), "1.1.1.0/24".parse().unwrap()],
+            deny: vec!["10.0.0.1/32".parse().unwrap()],
+            allow_ports: vec![PortRange::try_from("80".to_string()).unwrap(), PortRange::try_from("8000-9000".to_string()).unwrap()],
+            deny_ports: vec![PortRange::try_from("8080".to_string()).unwrap()],
+        };
+        let policy = EgressPolicy::new(&egress_config);
+
+        assert_eq!(Ok(()), check(&policy, "10.0.0.2:80"));
+        assert_eq!(Ok(()), check(&policy, "1.1.1.1:8001"));
+        assert_eq!(Err(EgressDenied::DeniedAddress), check(&policy, "10.0.0.1:80"));
+        assert_eq!(Err(EgressDenied::DeniedPort), check(&policy, "1.1.1.1:8080"));
+        assert_eq!(Err(EgressDenied::PortNotAllowed), check(&policy, "1.1.1.1:443"));
+        assert_eq!(Err(EgressDenied::AddressNotAllowed), check(&policy, "8.8.8.8:80"));
+    }
+
+    #[test]
+    fn port_range_test() {
+        assert_eq!(PortRange { start: 80, end: 80 }, PortRange::try_from("80".to_string()).unwrap());
+        assert_eq!(PortRange { start: 1, end: 1024 }, PortRange::try_from("1-1024".to_string()).unwrap());
+        assert!(PortRange::try_from("1024-1".to_string()).is_err());
+        assert!(PortRange::try_from("http".to_string()).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use domain_name_actor::actor::ActorHandle;
+use configuration::{EndpointConfiguration, GoalConfiguration};
+use crate::egress::EgressPolicy;
+use crate::outbound::OutboundPolicy;
+
+pub const DEFAULT_PATH: &str = "/goal";
+
+// 一个 WebSocket 路径及其策略
+pub struct Endpoint {
+    pub path: String,
+    allowed_users: Option<HashSet<String>>,
+    pub egress_policy: EgressPolicy,
+    pub domain_name_handle: ActorHandle,
+    // 未配置时使用全局的出口
+    pub outbound: Option<OutboundPolicy>,
+}
+
+impl Endpoint {
+    // 未认证时 `user` 为 `None`
+    pub fn is_user_allowed(&self, user: Option<&str>) -> bool {
+        match (&self.allowed_users, user) {
+            (None, _) => true,
+            (Some(users), Some(user)) => users.contains(user),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+// 相同的 DNS 服务器共用一个 actor，共享查询结果；重新加载配置时沿用，查询期限改变时重新创建
+#[derive(Clone, Default)]
+pub struct Resolvers {
+    domain_name_handles: Arc<Mutex<HashMap<SocketAddr, ActorHandle>>>,
+}
+
+impl Resolvers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_create(&self, server_address: SocketAddr, timeout: Duration) -> ActorHandle {
+        let mut handles = self.domain_name_handles.lock().unwrap();
+        let handle = handles.entry(server_address)
+            .or_insert_with(|| ActorHandle::new(server_address, timeout));
+        if handle.timeout() != timeout {
+            *handle = ActorHandle::new(server_address, timeout);
+        }
+        handle.clone()
+    }
+
+    // 各 DNS 服务器及其 actor
+    pub fn list(&self) -> Vec<(SocketAddr, ActorHandle)> {
+        self.domain_name_handles.lock().unwrap()
+            .iter()
+            .map(|(addr, handle)| (*addr, handle.clone()))
+            .collect()
+    }
+}
+
+// 按路径查找 endpoint
+pub struct Endpoints {
+    endpoints: HashMap<String, Arc<Endpoint>>,
+}
+
+impl Endpoints {
+    pub fn new(goal_config: &GoalConfiguration, resolvers: &Resolvers) -> Result<Self, String> {
+        let default_endpoint = EndpointConfiguration {
+            path: DEFAULT_PATH.to_string(),
+            users: None,
+            egress: None,
+            dns: None,
+            outbound: None,
+        };
+        let endpoint_configs = if goal_config.endpoints.is_empty() {
+            vec![default_endpoint]
+        } else {
+            goal_config.endpoints.clone()
+        };
+
+        let mut endpoints = HashMap::new();
+
+        for endpoint_config in endpoint_configs {
+            if !endpoint_config.path.starts_with('/') {
+                return Err(format!("endpoint path '{}' must start with '/'", endpoint_config.path));
+            }
+            if endpoint_config.users.is_some() && goal_config.auth.is_none() {
+                return Err(format!("endpoint '{}' restricts users but [auth] is not configured", endpoint_config.path));
+            }
+
+            let dns_config = endpoint_config.dns.as_ref().unwrap_or(&goal_config.dns);
+            let domain_name_handle = resolvers.get_or_create(dns_config.server_address, Duration::from_millis(dns_config.timeout_ms));
+
+            let egress_config = endpoint_config.egress.as_ref().unwrap_or(&goal_config.egress);
+
+            let outbound = match &endpoint_config.outbound {
+                Some(outbound_config) => Some(OutboundPolicy::new(outbound_config)
+                    .map_err(|e| format!("endpoint '{}': {}", endpoint_config.path, e))?),
+                None => None,
+            };
+
+            let endpoint = Endpoint {
+                path: endpoint_config.path.clone(),
+                allowed_users: endpoint_config.users.map(|users| users.into_iter().collect()),
+                egress_policy: EgressPolicy::new(egress_config),
+                domain_name_handle,
+                outbound,
+            };
+
+            if endpoints.insert(endpoint_config.path.clone(), Arc::new(endpoint)).is_some() {
+                return Err(format!("duplicate endpoint path '{}'", endpoint_config.path));
+            }
+        }
+
+        Ok(Self { endpoints })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Arc<Endpoint>> {
+        self.endpoints.get(path)
+    }
+}
@@ -0,0 +1,315 @@
+// 不属于隧道的请求（路径错误、不是 WebSocket 升级请求、认证失败）交给回落处理，
+// 使 goal 看起来像普通的 Web 服务器。
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use configuration::FallbackConfiguration;
+use crate::http_head::RequestHead;
+
+#[derive(Debug, Clone)]
+pub enum Fallback {
+    // 返回 404
+    NotFound,
+    // 以静态网站的形式提供目录中的文件
+    StaticDir(PathBuf),
+    // 原样转发给本机的其他 Web 服务器
+    ReverseProxy {
+        backend: SocketAddr,
+        idle_timeout: Duration,
+        max_lifetime: Duration,
+    },
+}
+
+impl Fallback {
+    pub fn new(fallback_config: Option<&FallbackConfiguration>) -> Self {
+        match fallback_config {
+            Some(FallbackConfiguration { backend: Some(backend), idle_timeout_secs, max_lifetime_secs, .. }) => {
+                Fallback::ReverseProxy {
+                    backend: *backend,
+                    idle_timeout: Duration::from_secs(*idle_timeout_secs),
+                    max_lifetime: Duration::from_secs(*max_lifetime_secs),
+                }
+            }
+            Some(FallbackConfiguration { static_dir: Some(dir), .. }) => Fallback::StaticDir(dir.clone()),
+            _ => Fallback::NotFound,
+        }
+    }
+
+    // `stream` 需要重放已读取的数据；`head` 为 `None` 表示请求头无法解析
+    pub async fn serve<S>(&self, mut stream: S, head: Option<&RequestHead>) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match (self, head) {
+            (Fallback::NotFound, Some(head)) => {
+                write_response(&mut stream, head, 404, "text/html", NOT_FOUND_PAGE.as_bytes()).await
+            }
+            (Fallback::StaticDir(dir), Some(head)) => serve_static(&mut stream, head, dir).await,
+            (Fallback::NotFound, None) | (Fallback::StaticDir(_), None) => {
+                stream.write_all(BAD_REQUEST_RESPONSE.as_bytes()).await?;
+                stream.shutdown().await
+            }
+            (Fallback::ReverseProxy { backend, idle_timeout, max_lifetime }, _) => {
+                let backend_stream = TcpStream::connect(backend).await?;
+                tokio::time::timeout(*max_lifetime, proxy(stream, backend_stream, *idle_timeout)).await
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "fallback max lifetime exceeded")))
+            }
+        }
+    }
+}
+
+const BAD_REQUEST_RESPONSE: &str = "HTTP/1.1 400 Bad Request\r\nContent-Type: text/html\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+const NOT_FOUND_PAGE: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n</body>\r\n</html>\r\n";
+
+// 双向转发，两个方向都结束时返回；`idle_timeout` 内两个方向都没有数据时返回 `TimedOut`
+async fn proxy<S>(stream: S, backend_stream: TcpStream, idle_timeout: Duration) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+    let (mut backend_read, mut backend_write) = backend_stream.into_split();
+    let mut client_buf = vec![0; 16 << 10];
+    let mut backend_buf = vec![0; 16 << 10];
+    let (mut client_done, mut backend_done) = (false, false);
+
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    while !client_done || !backend_done {
+        tokio::select! {
+            n = client_read.read(&mut client_buf), if !client_done => match n? {
+                0 => {
+                    client_done = true;
+                    backend_write.shutdown().await?;
+                }
+                n => backend_write.write_all(&client_buf[..n]).await?,
+            },
+            n = backend_read.read(&mut backend_buf), if !backend_done => match n? {
+                0 => {
+                    backend_done = true;
+                    client_write.shutdown().await?;
+                }
+                n => client_write.write_all(&backend_buf[..n]).await?,
+            },
+            _ = &mut idle => return Err(io::Error::new(io::ErrorKind::TimedOut, "fallback idle timeout")),
+        }
+        idle.as_mut().reset(Instant::now() + idle_timeout);
+    }
+    Ok(())
+}
+
+async fn serve_static<S>(stream: &mut S, head: &RequestHead, dir: &Path) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    if head.method != "GET" && head.method != "HEAD" {
+        return write_response(stream, head, 405, "text/plain", b"Method Not Allowed").await;
+    }
+
+    let mut file_path = match resolve_static_path(dir, head.path()) {
+        Some(file_path) => file_path,
+        None => return write_response(stream, head, 404, "text/html", NOT_FOUND_PAGE.as_bytes()).await,
+    };
+    if tokio::fs::metadata(&file_path).await.is_ok_and(|metadata| metadata.is_dir()) {
+        file_path.push("index.html");
+    }
+
+    let (file, len) = match open_file(&file_path).await {
+        Ok(opened) => opened,
+        Err(_) => return write_response(stream, head, 404, "text/html", NOT_FOUND_PAGE.as_bytes()).await,
+    };
+    write_response_head(stream, 200, content_type(&file_path), len).await?;
+    if head.method != "HEAD" {
+        tokio::io::copy(&mut file.take(len), stream).await?;
+    }
+    stream.shutdown().await
+}
+
+// 打开普通文件，返回文件及其长度
+async fn open_file(path: &Path) -> io::Result<(tokio::fs::File, u64)> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a regular file"));
+    }
+    Ok((file, metadata.len()))
+}
+
+// 把请求路径映射为目录中的文件，拒绝跳出目录的路径；以 "/" 结尾时为目录中的 "index.html"，
+// 指向目录而不以 "/" 结尾的路径由调用者判断
+pub fn resolve_static_path(dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+
+    let mut file_path = dir.to_path_buf();
+    for component in Path::new(&decoded).components() {
+        match component {
+            Component::Normal(part) => file_path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    if decoded.ends_with('/') {
+        file_path.push("index.html");
+    }
+
+    Some(file_path)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+pub async fn write_response<S>(
+    stream: &mut S,
+    head: &RequestHead,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_response_head(stream, status, content_type, body.len() as u64).await?;
+    if head.method != "HEAD" {
+        stream.write_all(body).await?;
+    }
+    stream.shutdown().await
+}
+
+async fn write_response_head<S>(stream: &mut S, status: u16, content_type: &str, content_length: u64) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("");
+
+    let response_head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, content_length,
+    );
+    stream.write_all(response_head.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_static_path_test() {
+        let dir = Path::new("/srv/www");
+
+        assert_eq!(Some(PathBuf::from("/srv/www/index.html")), resolve_static_path(dir, "/"));
+        assert_eq!(Some(PathBuf::from("/srv/www/css/a b.css")), resolve_static_path(dir, "/css/a%20b.css"));
+        assert_eq!(Some(PathBuf::from("/srv/www/blog/index.html")), resolve_static_path(dir, "/blog/"));
+        assert_eq!(None, resolve_static_path(dir, "/../etc/passwd"));
+        assert_eq!(None, resolve_static_path(dir, "/%2e%2e/etc/passwd"));
+        assert_eq!(None, resolve_static_path(dir, "/%zz"));
+    }
+
+    fn get(target: &str) -> RequestHead {
+        RequestHead { method: "GET".to_string(), target: target.to_string(), headers: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn serve_static_test() {
+        let dir = std::env::temp_dir().join(format!("goal_fallback_static_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("blog")).unwrap();
+        std::fs::write(dir.join("blog/index.html"), b"blog").unwrap();
+
+        // 指向目录的路径使用其中的 index.html
+        let fallback = Fallback::StaticDir(dir.clone());
+        let (server, mut client) = tokio::io::duplex(1024);
+        fallback.serve(server, Some(&get("/blog"))).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("Content-Length: 4\r\nConnection: close\r\n\r\nblog"), "{}", response);
+
+        let (server, mut client) = tokio::io::duplex(1024);
+        fallback.serve(server, Some(&get("/missing"))).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn reverse_proxy_idle_test() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = Fallback::ReverseProxy {
+            backend: listener.local_addr().unwrap(),
+            idle_timeout: Duration::from_millis(50),
+            max_lifetime: Duration::from_secs(10),
+        };
+        let (server, mut client) = tokio::io::duplex(1024);
+
+        let backend = async {
+            let (mut backend_stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            backend_stream.read_exact(&mut buf).await.unwrap();
+            backend_stream.write_all(b"pong").await.unwrap();
+            // 之后不再发送数据，连接保持打开
+            backend_stream
+        };
+        let client_side = async {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"pong", &buf);
+        };
+        let started_at = Instant::now();
+        let (ret, _backend_stream, _) = tokio::join!(fallback.serve(server, None), backend, client_side);
+
+        assert_eq!(io::ErrorKind::TimedOut, ret.unwrap_err().kind());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,116 @@
+// 经反向代理连接时，从 Forwarded 或 X-Forwarded-For 请求头中取得客户端的地址
+//
+// 请求头可以由客户端伪造，只有来自受信任代理的连接才使用：
+// 从右向左跳过受信任的代理，第一个不受信任的地址就是客户端
+
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use crate::http_head::RequestHead;
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        Self { nets }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    // `peer_addr` 是直接连接 goal 的地址（启用 PROXY protocol 时为其中的源地址）
+    pub fn client_addr(&self, peer_addr: SocketAddr, head: &RequestHead) -> SocketAddr {
+        if !self.contains(peer_addr.ip()) {
+            return peer_addr;
+        }
+
+        // 两者都有时以标准的 Forwarded 为准
+        let forwarded: Vec<&str> = head.header_values("forwarded").collect();
+        let hops: Vec<Option<SocketAddr>> = if !forwarded.is_empty() {
+            forwarded.iter()
+                .flat_map(|value| value.split(','))
+                .map(parse_forwarded_for)
+                .collect()
+        } else {
+            head.header_values("x-forwarded-for")
+                .flat_map(|value| value.split(','))
+                .map(|hop| parse_node(hop.trim()))
+                .collect()
+        };
+
+        let mut client_addr = peer_addr;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(addr) => {
+                    client_addr = addr;
+                    if !self.contains(addr.ip()) {
+                        break;
+                    }
+                }
+                // 无法识别的地址（如 "unknown"），停在最后一个受信任的代理
+                None => break,
+            }
+        }
+        client_addr
+    }
+}
+
+// Forwarded 的一个元素，如 `for=192.0.2.60;proto=https;by=203.0.113.43`
+// https://datatracker.ietf.org/doc/html/rfc7239#section-4
+fn parse_forwarded_for(element: &str) -> Option<SocketAddr> {
+    element.split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+        .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+}
+
+// "192.0.2.60"、"192.0.2.60:4711"、"2001:db8::1"、"[2001:db8::1]"、"[2001:db8::1]:4711"，
+// 没有端口时端口为 0
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .unwrap_or(node);
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use crate::http_head::parse_request_head;
+
+    fn request(headers: &str) -> RequestHead {
+        let raw = format!("GET /goal HTTP/1.1\r\nHost: a\r\n{}\r\n", headers);
+        parse_request_head(&BytesMut::from(raw.as_str())).unwrap().unwrap()
+    }
+
+    #[test]
+    fn client_addr_test() {
+        let trusted = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let proxy: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let untrusted: SocketAddr = "198.51.100.1:50000".parse().unwrap();
+        let client_addr = |peer, headers| trusted.client_addr(peer, &request(headers)).to_string();
+
+        // 跳过受信任的代理，不使用客户端伪造的部分
+        let xff = "X-Forwarded-For: 192.0.2.9, 192.0.2.1, 10.0.0.2\r\n";
+        assert_eq!("192.0.2.1:0", client_addr(proxy, xff));
+        assert_eq!("198.51.100.1:50000", client_addr(untrusted, xff));
+        assert_eq!("192.0.2.1:0", client_addr(proxy, "X-Forwarded-For: 192.0.2.9\r\nX-Forwarded-For: 192.0.2.1\r\n"));
+
+        let forwarded = "Forwarded: for=192.0.2.9, for=\"[2001:db8::1]:4711\";proto=https\r\nX-Forwarded-For: 192.0.2.1\r\n";
+        assert_eq!("[2001:db8::1]:4711", client_addr(proxy, forwarded));
+
+        assert_eq!("10.0.0.2:0", client_addr(proxy, "X-Forwarded-For: unknown, 10.0.0.2\r\n"));
+        assert_eq!("10.0.0.1:50000", client_addr(proxy, ""));
+    }
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// 请求头的最大长度，超出后不再等待，直接视为无效请求
+pub const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+// HTTP 请求的起始行与请求头
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    // 包含查询字符串，如 "/goal?a=1"
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    // 请求头名称不区分大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // 同名的请求头可能出现多次
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.1
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let has_token = |name: &str, token: &str| {
+            self.header(name)
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        };
+
+        self.method == "GET"
+            && has_token("connection", "upgrade")
+            && has_token("upgrade", "websocket")
+            && self.header("sec-websocket-key").is_some()
+    }
+}
+
+// 读取并解析请求头，数据不完整时继续读取。
+// 读取到的全部数据（可能包含请求头之后的部分）保留在 `buf` 中，解析失败时也是如此。
+pub async fn read_request_head<S>(stream: &mut S, buf: &mut BytesMut) -> io::Result<RequestHead>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before request head"));
+        }
+
+        if let Some(head) = parse_request_head(buf)? {
+            return Ok(head);
+        }
+
+        if buf.len() >= MAX_HEAD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+    }
+}
+
+// 数据不完整时返回 `Ok(None)`
+pub fn parse_request_head(buf: &BytesMut) -> io::Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(_)) => {
+            let head = RequestHead {
+                method: req.method.unwrap_or_default().to_string(),
+                target: req.path.unwrap_or_default().to_string(),
+                headers: req.headers.iter()
+                    .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).to_string()))
+                    .collect(),
+            };
+            Ok(Some(head))
+        }
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+// 先返回已经读取的数据，再从内部的流中读取。
+// 请求头已被读出，交给 WebSocket 握手或回落处理时需要重放这部分数据。
+pub struct PrefixedStream<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Bytes, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_head_test() {
+        let partial = BytesMut::from("GET /goal HTTP/1.1\r\nHost: 127.0.0.1\r\n");
+        assert!(parse_request_head(&partial).unwrap().is_none());
+
+        let complete = BytesMut::from(concat!(
+            "GET /goal?x=1 HTTP/1.1\r\n",
+            "Host: 127.0.0.1:18030\r\n",
+            "Connection: keep-alive, Upgrade\r\n",
+            "Upgrade: websocket\r\n",
+            "Sec-WebSocket-Version: 13\r\n",
+            "Sec-WebSocket-Key: 4qua2jXK9gwaSWDVWZZ8Ow==\r\n",
+            "\r\n",
+        ));
+        let head = parse_request_head(&complete).unwrap().unwrap();
+        assert_eq!("GET", head.method);
+        assert_eq!("/goal", head.path());
+        assert_eq!(Some("127.0.0.1:18030"), head.header("HOST"));
+        assert!(head.is_websocket_upgrade());
+
+        let plain = BytesMut::from("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert!(!parse_request_head(&plain).unwrap().unwrap().is_websocket_upgrade());
+
+        let garbage = BytesMut::from(&b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"[..]);
+        assert!(parse_request_head(&garbage).is_err());
+    }
+
+    #[tokio::test]
+    async fn prefixed_stream_test() {
+        let inner: &[u8] = b" world";
+        let mut stream = PrefixedStream::new(Bytes::from_static(b"hello"), inner);
+
+        let mut s = String::new();
+        stream.read_to_string(&mut s).await.unwrap();
+        assert_eq!("hello world", s);
+    }
+}
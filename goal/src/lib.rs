@@ -1 +1,22 @@
+pub mod access_log;
+pub mod accounts;
+pub mod admin;
+pub mod auth;
+pub mod ban;
 pub mod cli_args;
+pub mod connect;
+pub mod egress;
+pub mod endpoint;
+pub mod fallback;
+pub mod forwarded;
+pub mod http_head;
+pub mod limits;
+pub mod listener;
+pub mod metered;
+pub mod metrics;
+pub mod outbound;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod replay;
+pub mod session;
+pub mod upstream;
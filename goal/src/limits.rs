@@ -0,0 +1,293 @@
+// 连接数限制：连接总数、每个客户端 IP 的连接数、尚未建立隧道的连接数
+//
+// 超过限制时排队等待空位，或在 WebSocket 升级之前直接返回 503，避免连接洪水耗尽文件描述符
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use configuration::LimitsConfiguration;
+
+const SERVICE_UNAVAILABLE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum LimitError {
+    TooManySessions,
+    TooManySessionsPerIp,
+    TooManyPendingHandshakes,
+}
+
+impl LimitError {
+    // 用作指标的标签
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitError::TooManySessions => "max_sessions",
+            LimitError::TooManySessionsPerIp => "max_sessions_per_ip",
+            LimitError::TooManyPendingHandshakes => "max_pending_handshakes",
+        }
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            LimitError::TooManySessions => "too many sessions",
+            LimitError::TooManySessionsPerIp => "too many sessions from the client IP",
+            LimitError::TooManyPendingHandshakes => "too many pending handshakes",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    sessions: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    pending_handshakes: usize,
+}
+
+impl Counts {
+    fn check_ip(&self, config: &LimitsConfiguration, client_ip: IpAddr) -> Result<(), LimitError> {
+        let ip_sessions = self.per_ip.get(&client_ip).copied().unwrap_or(0);
+        if config.max_sessions_per_ip.is_some_and(|max| ip_sessions >= max) {
+            return Err(LimitError::TooManySessionsPerIp);
+        }
+        Ok(())
+    }
+
+    fn add_ip(&mut self, client_ip: IpAddr) {
+        *self.per_ip.entry(client_ip).or_insert(0) += 1;
+    }
+
+    fn remove_ip(&mut self, client_ip: IpAddr) {
+        if let Some(ip_sessions) = self.per_ip.get_mut(&client_ip) {
+            *ip_sessions -= 1;
+            if *ip_sessions == 0 {
+                self.per_ip.remove(&client_ip);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Limits {
+    // 重新加载配置时只替换 `config`，已有的计数保持不变
+    config: Mutex<LimitsConfiguration>,
+    counts: Mutex<Counts>,
+    // 有连接结束或完成握手时通知排队的连接
+    released: Notify,
+}
+
+impl Limits {
+    pub fn new(limits_config: &LimitsConfiguration) -> Self {
+        Self {
+            config: Mutex::new(limits_config.clone()),
+            ..Self::default()
+        }
+    }
+
+    pub fn reconfigure(&self, limits_config: &LimitsConfiguration) {
+        *self.config.lock().unwrap() = limits_config.clone();
+        // 限制可能放宽了
+        self.released.notify_waiters();
+    }
+
+    // 接受来自 `client_ip` 的连接，连接开始时处于握手状态。
+    // 经受信任的代理连接时，读到请求头之前不知道客户端 IP，此时为 `None`，之后由 `Permit::set_client_ip` 设置。
+    // 配置了 `queue_timeout_ms` 时，超过限制的连接排队等待，超时后返回错误
+    pub async fn admit(self: &Arc<Self>, client_ip: Option<IpAddr>) -> Result<Permit, LimitError> {
+        let queue_timeout = self.config.lock().unwrap().queue_timeout_ms.map(Duration::from_millis);
+        let deadline = match queue_timeout {
+            Some(queue_timeout) => tokio::time::Instant::now() + queue_timeout,
+            None => return self.try_admit(client_ip),
+        };
+
+        loop {
+            // 先登记再检查，避免错过检查之后、等待之前的通知
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let e = match self.try_admit(client_ip) {
+                Ok(permit) => return Ok(permit),
+                Err(e) => e,
+            };
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(e);
+            }
+        }
+    }
+
+    pub fn try_admit(self: &Arc<Self>, client_ip: Option<IpAddr>) -> Result<Permit, LimitError> {
+        let config = self.config.lock().unwrap().clone();
+        let mut counts = self.counts.lock().unwrap();
+
+        if config.max_sessions.is_some_and(|max| counts.sessions >= max) {
+            return Err(LimitError::TooManySessions);
+        }
+        if let Some(client_ip) = client_ip {
+            counts.check_ip(&config, client_ip)?;
+        }
+        if config.max_pending_handshakes.is_some_and(|max| counts.pending_handshakes >= max) {
+            return Err(LimitError::TooManyPendingHandshakes);
+        }
+
+        counts.sessions += 1;
+        if let Some(client_ip) = client_ip {
+            counts.add_ip(client_ip);
+        }
+        counts.pending_handshakes += 1;
+
+        Ok(Permit {
+            limits: self.clone(),
+            client_ip,
+            pending_handshake: true,
+        })
+    }
+
+    // 连接总数、尚未建立隧道的连接数
+    pub fn counts(&self) -> (usize, usize) {
+        let counts = self.counts.lock().unwrap();
+        (counts.sessions, counts.pending_handshakes)
+    }
+}
+
+// 连接结束时丢弃，归还名额
+pub struct Permit {
+    limits: Arc<Limits>,
+    client_ip: Option<IpAddr>,
+    pending_handshake: bool,
+}
+
+impl Permit {
+    // 从代理的请求头中得到客户端 IP 后，按该 IP 计数
+    pub fn set_client_ip(&mut self, client_ip: IpAddr) -> Result<(), LimitError> {
+        if self.client_ip == Some(client_ip) {
+            return Ok(());
+        }
+
+        let config = self.limits.config.lock().unwrap().clone();
+        {
+            let mut counts = self.limits.counts.lock().unwrap();
+            counts.check_ip(&config, client_ip)?;
+            counts.add_ip(client_ip);
+            if let Some(previous) = self.client_ip.replace(client_ip) {
+                counts.remove_ip(previous);
+            }
+        }
+        self.limits.released.notify_waiters();
+        Ok(())
+    }
+
+    // 已建立隧道或交给回落处理
+    pub fn handshake_done(&mut self) {
+        if self.pending_handshake {
+            self.pending_handshake = false;
+            self.limits.counts.lock().unwrap().pending_handshakes -= 1;
+            self.limits.released.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        {
+            let mut counts = self.limits.counts.lock().unwrap();
+            counts.sessions -= 1;
+            if self.pending_handshake {
+                counts.pending_handshakes -= 1;
+            }
+            if let Some(client_ip) = self.client_ip {
+                counts.remove_ip(client_ip);
+            }
+        }
+        self.limits.released.notify_waiters();
+    }
+}
+
+// 不读取请求，直接返回 503
+pub async fn reject<S>(stream: &mut S) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(SERVICE_UNAVAILABLE).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_sessions: usize, max_sessions_per_ip: usize, max_pending_handshakes: usize) -> LimitsConfiguration {
+        LimitsConfiguration {
+            max_sessions: Some(max_sessions),
+            max_sessions_per_ip: Some(max_sessions_per_ip),
+            max_pending_handshakes: Some(max_pending_handshakes),
+            queue_timeout_ms: None,
+        }
+    }
+
+    #[test]
+    fn try_admit_test() {
+        let ip_a: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip_b: IpAddr = "192.0.2.2".parse().unwrap();
+        let limits = Arc::new(Limits::new(&limits(3, 2, 2)));
+
+        let mut first = limits.try_admit(Some(ip_a)).unwrap();
+        let _second = limits.try_admit(Some(ip_a)).unwrap();
+        assert_eq!(Err(LimitError::TooManySessionsPerIp), limits.try_admit(Some(ip_a)).map(|_| ()));
+        assert_eq!(Err(LimitError::TooManyPendingHandshakes), limits.try_admit(Some(ip_b)).map(|_| ()));
+
+        first.handshake_done();
+        let _third = limits.try_admit(Some(ip_b)).unwrap();
+        assert_eq!(Err(LimitError::TooManySessions), limits.try_admit(Some(ip_b)).map(|_| ()));
+        assert_eq!((3, 2), limits.counts());
+
+        drop(first);
+        assert_eq!((2, 2), limits.counts());
+        assert!(limits.try_admit(Some(ip_a)).is_err());
+        limits.reconfigure(&LimitsConfiguration::default());
+        assert!(limits.try_admit(Some(ip_a)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn queue_test() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut limits_config = limits(1, 1, 1);
+        limits_config.queue_timeout_ms = Some(50);
+        let limits = Arc::new(Limits::new(&limits_config));
+
+        let first = limits.admit(Some(ip)).await.unwrap();
+        assert_eq!(Err(LimitError::TooManySessions), limits.admit(Some(ip)).await.map(|_| ()));
+
+        let queued = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.admit(Some(ip)).await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(first);
+        assert!(queued.await.unwrap());
+    }
+
+    #[test]
+    fn set_client_ip_test() {
+        let ip_a: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip_b: IpAddr = "192.0.2.2".parse().unwrap();
+        let limits = Arc::new(Limits::new(&limits(3, 1, 3)));
+
+        // 经代理连接时先不按 IP 计数
+        let mut first = limits.try_admit(None).unwrap();
+        let mut second = limits.try_admit(None).unwrap();
+        first.set_client_ip(ip_a).unwrap();
+        assert_eq!(Err(LimitError::TooManySessionsPerIp), second.set_client_ip(ip_a));
+        second.set_client_ip(ip_b).unwrap();
+        assert!(limits.try_admit(Some(ip_b)).is_err());
+
+        drop(second);
+        assert!(limits.try_admit(Some(ip_b)).is_ok());
+    }
+}
@@ -0,0 +1,401 @@
+// goal 的监听地址，TCP 或 Unix 套接字，每个监听地址可以单独启用 TLS、限定 WebSocket 路径
+//
+// 重新加载配置时，地址与套接字选项不变的监听地址沿用已有的套接字，
+// 只更新 TLS 证书、路径与 Unix 套接字文件的权限，已建立的连接不受影响
+
+use std::collections::HashSet;
+use std::fs::{File, Permissions};
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use configuration::{ListenAddress, ListenerConfiguration, TlsConfiguration};
+use crate::endpoint::Endpoints;
+use crate::forwarded::TrustedProxies;
+
+const BACKLOG: i32 = 1024;
+
+// Unix 套接字的连接没有 IP 地址，记为 127.0.0.1:0。
+// 经同一主机上的反向代理连接时，在 `trusted_proxies` 中加入 127.0.0.1 以使用其请求头中的客户端地址
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+pub struct Listener {
+    pub address: ListenAddress,
+    // 重新加载配置时按地址与套接字选项判断能否沿用
+    reuse_port: bool,
+    ipv6_only: bool,
+    socket: Arc<ListenSocket>,
+    pub policy: ListenerPolicy,
+}
+
+enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+// 关闭时删除套接字文件
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// 监听地址接受连接后的处理方式
+#[derive(Clone)]
+pub struct ListenerPolicy {
+    tls: Option<TlsAcceptor>,
+    paths: Option<Arc<HashSet<String>>>,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: TrustedProxies,
+}
+
+impl Listener {
+    // 按配置监听所有地址，`current` 中地址与套接字选项相同的监听地址沿用已有的套接字。
+    // 任何一个地址失败时返回错误，此时已有的监听地址不受影响
+    pub fn bind_all(
+        listener_configs: &[ListenerConfiguration],
+        current: &[Listener],
+        endpoints: &Endpoints,
+    ) -> Result<Vec<Listener>, String> {
+        let mut listeners: Vec<Listener> = Vec::with_capacity(listener_configs.len());
+        for listener_config in listener_configs {
+            if listeners.iter().any(|l| l.address == listener_config.address) {
+                return Err(format!("duplicate listener {}", listener_config.address));
+            }
+
+            let policy = ListenerPolicy::new(listener_config, endpoints)
+                .map_err(|e| format!("invalid listener {}, {}", listener_config.address, e))?;
+            // 同一路径的 Unix 套接字总是沿用，以免旧的套接字关闭时删除新的套接字文件
+            let existing = current.iter().find(|l| {
+                l.address == listener_config.address
+                    && (matches!(l.address, ListenAddress::Unix(_))
+                        || (l.reuse_port == listener_config.reuse_port && l.ipv6_only == listener_config.ipv6_only))
+            });
+            let socket = match existing {
+                Some(existing) => {
+                    if let (ListenSocket::Unix(unix_socket), Some(mode)) = (existing.socket.as_ref(), listener_config.mode) {
+                        set_mode(&unix_socket.path, mode)
+                            .map_err(|e| format!("failed to set mode of {}, {}", listener_config.address, e))?;
+                    }
+                    existing.socket.clone()
+                }
+                None => {
+                    let socket = bind(listener_config)
+                        .map_err(|e| format!("failed to bind {}, {}", listener_config.address, e))?;
+                    Arc::new(socket)
+                }
+            };
+
+            listeners.push(Listener {
+                address: listener_config.address.clone(),
+                reuse_port: listener_config.reuse_port,
+                ipv6_only: listener_config.ipv6_only,
+                socket,
+                policy,
+            });
+        }
+        Ok(listeners)
+    }
+
+    // 不借用 `self`，等待连接时可以替换监听地址
+    pub fn accept(&self) -> impl Future<Output = Option<(ClientStream, SocketAddr)>> + 'static {
+        let socket = self.socket.clone();
+        async move {
+            match socket.as_ref() {
+                ListenSocket::Tcp(listener) => {
+                    let (stream, addr) = transfer::tcp_accept::accept(listener).await?;
+                    Some((ClientStream::Tcp(stream), addr))
+                }
+                ListenSocket::Unix(unix_socket) => {
+                    let (stream, _) = transfer::tcp_accept::accept(&unix_socket.listener).await?;
+                    Some((ClientStream::Unix(stream), UNIX_PEER_ADDR))
+                }
+            }
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.policy.is_tls()
+    }
+}
+
+impl ListenerPolicy {
+    fn new(listener_config: &ListenerConfiguration, endpoints: &Endpoints) -> Result<Self, String> {
+        let tls = match &listener_config.tls {
+            Some(tls_config) => Some(tls_acceptor(tls_config)?),
+            None => None,
+        };
+
+        let paths = match &listener_config.paths {
+            Some(paths) => {
+                if let Some(path) = paths.iter().find(|path| endpoints.get(path).is_none()) {
+                    return Err(format!("path '{}' is not an endpoint", path));
+                }
+                Some(Arc::new(paths.iter().cloned().collect()))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            tls,
+            paths,
+            proxy_protocol: listener_config.proxy_protocol,
+            trusted_proxies: TrustedProxies::new(listener_config.trusted_proxies.clone()),
+        })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn is_path_allowed(&self, path: &str) -> bool {
+        self.paths.as_ref().is_none_or(|paths| paths.contains(path))
+    }
+
+    // 启用 TLS 时完成 TLS 握手
+    pub async fn handshake(&self, stream: ClientStream) -> io::Result<ClientStream> {
+        match &self.tls {
+            Some(acceptor) => Ok(ClientStream::Tls(Box::new(acceptor.accept(stream).await?))),
+            None => Ok(stream),
+        }
+    }
+}
+
+fn bind(listener_config: &ListenerConfiguration) -> io::Result<ListenSocket> {
+    match &listener_config.address {
+        ListenAddress::Tcp(address) => bind_tcp(*address, listener_config).map(ListenSocket::Tcp),
+        ListenAddress::Unix(path) => bind_unix(path, listener_config.mode).map(ListenSocket::Unix),
+    }
+}
+
+fn bind_tcp(address: SocketAddr, listener_config: &ListenerConfiguration) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if listener_config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    // 明确设置，不依赖系统的 net.ipv6.bindv6only
+    if address.is_ipv6() {
+        socket.set_only_v6(listener_config.ipv6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixSocket> {
+    // 删除上次退出时残留的套接字文件，仍有进程监听时不删除
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket file"));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use"));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let unix_socket = UnixSocket { listener, path: path.to_path_buf() };
+    if let Some(mode) = mode {
+        set_mode(path, mode)?;
+    }
+    Ok(unix_socket)
+}
+
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    std::fs::set_permissions(path, Permissions::from_mode(mode))
+}
+
+fn tls_acceptor(tls_config: &TlsConfiguration) -> Result<TlsAcceptor, String> {
+    let certs = rustls_pemfile::certs(&mut open(&tls_config.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate {:?}, {}", tls_config.cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {:?}", tls_config.cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&tls_config.key_path)?)
+        .map_err(|e| format!("invalid private key {:?}, {}", tls_config.key_path, e))?
+        .ok_or_else(|| format!("no private key in {:?}", tls_config.key_path))?;
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("invalid certificate or private key, {}", e))?;
+
+    // WebSocket 升级与回落都是 HTTP/1.1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn open(path: &std::path::Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to open {:?}, {}", path, e))
+}
+
+// soccer 的连接
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream<ClientStream>>),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use configuration::GoalConfiguration;
+    use crate::endpoint::Resolvers;
+
+    fn endpoints() -> Endpoints {
+        let goal_config: GoalConfiguration = config::Config::builder()
+            .add_source(config::File::from_str(
+                "[server]\nip_address = \"127.0.0.1\"\nport = 0\n[dns]\nserver_address = \"127.0.0.1:53\"\n",
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        Endpoints::new(&goal_config, &Resolvers::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bind_all_test() {
+        let endpoints = endpoints();
+        let mut listener_config = ListenerConfiguration::plain(ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()));
+        listener_config.paths = Some(vec!["/goal".to_string()]);
+        let listeners = Listener::bind_all(&[listener_config.clone()], &[], &endpoints).unwrap();
+        assert!(!listeners[0].is_tls());
+        assert!(listeners[0].policy.is_path_allowed("/goal"));
+        assert!(!listeners[0].policy.is_path_allowed("/other"));
+
+        // 沿用已有的套接字
+        let reloaded = Listener::bind_all(&[listener_config.clone()], &listeners, &endpoints).unwrap();
+        assert!(Arc::ptr_eq(&listeners[0].socket, &reloaded[0].socket));
+
+        listener_config.paths = Some(vec!["/other".to_string()]);
+        assert!(Listener::bind_all(&[listener_config], &[], &endpoints).is_err());
+
+        let mut tls_config = ListenerConfiguration::plain(ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()));
+        tls_config.tls = Some(TlsConfiguration {
+            cert_path: "/nonexistent/cert.pem".into(),
+            key_path: "/nonexistent/key.pem".into(),
+        });
+        assert!(Listener::bind_all(&[tls_config], &[], &endpoints).is_err());
+    }
+
+    #[tokio::test]
+    async fn reuse_port_test() {
+        let mut listener_config = ListenerConfiguration::plain(ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()));
+        listener_config.reuse_port = true;
+        let first = bind_tcp("127.0.0.1:0".parse().unwrap(), &listener_config).unwrap();
+
+        let address = first.local_addr().unwrap();
+        assert!(bind_tcp(address, &listener_config).is_ok());
+
+        // 未设置 SO_REUSEPORT 时端口已被占用
+        listener_config.reuse_port = false;
+        assert!(bind_tcp(address, &listener_config).is_err());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_test() {
+        let address = "[::]:0".parse().unwrap();
+        let listener = match bind_tcp(address, &ListenerConfiguration::plain(ListenAddress::Tcp(address))) {
+            Ok(listener) => listener,
+            // 没有 IPv6 的环境
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(client.local_addr().unwrap().port(), peer_addr.port());
+    }
+
+    #[tokio::test]
+    async fn unix_test() {
+        let path = std::env::temp_dir().join(format!("goal_listener_{}.sock", std::process::id()));
+        // 残留的套接字文件
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let mut listener_config = ListenerConfiguration::plain(ListenAddress::Unix(path.clone()));
+        listener_config.mode = Some(0o600);
+        let listeners = Listener::bind_all(&[listener_config.clone()], &[], &endpoints()).unwrap();
+        assert_eq!(0o600, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+
+        // 正在使用的套接字文件
+        assert!(bind(&listener_config).is_err());
+
+        // 重新加载配置时沿用并更新权限
+        listener_config.mode = Some(0o660);
+        let reloaded = Listener::bind_all(&[listener_config], &listeners, &endpoints()).unwrap();
+        assert!(Arc::ptr_eq(&listeners[0].socket, &reloaded[0].socket));
+        assert_eq!(0o660, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (stream, peer_addr) = reloaded[0].accept().await.unwrap();
+        assert!(matches!(stream, ClientStream::Unix(_)));
+        assert_eq!(UNIX_PEER_ADDR, peer_addr);
+
+        drop(listeners);
+        drop(reloaded);
+        assert!(!path.exists());
+    }
+}
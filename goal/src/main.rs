@@ -1,19 +1,47 @@
 extern crate tokio;
 extern crate tokio_tungstenite;
 
-use bytes::{Buf, BytesMut};
-use tokio::net::{TcpListener, TcpStream};
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
-use log::{error, info};
+use log::{debug, error, info};
 use std::error::Error;
+use std::future::Future;
+use std::io;
 use std::net::{SocketAddr};
- use futures::{StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::util::SubscriberInitExt;
-use configuration::{GoalConfiguration};
-use domain_name_query_types::NameQuery;
+use configuration::{GoalConfiguration, TunnelConfiguration, WebSocketConfiguration};
+use goal::access_log::{AccessLog, AccessRecord};
+use goal::ban::Bans;
+use goal::accounts::{Accounts, AdmissionError, TunnelLease};
+use goal::auth::Authenticator;
+use goal::metered::{Direction, MeteredRead, MeteredWrite};
 use goal::cli_args::CliArgs;
+use goal::connect::Connector;
+use goal::endpoint::{Endpoint, Endpoints, Resolvers};
+use goal::fallback::{write_response, Fallback};
+use goal::http_head::{read_request_head, PrefixedStream, RequestHead};
+use protocol::{ConnectReply, DecodeError, Frame};
+use goal::session::{Session, Sessions};
+use goal::admin::Admin;
+use goal::limits::{self, Limits};
+use goal::proxy_protocol::read_proxy_header;
+use goal::listener::{ClientStream, Listener, ListenerPolicy};
+use goal::metrics;
+use transfer::{ReadBufferSize, RelayConfig, Teardown, WebSocketLimits};
+use transfer::traffic::RelaySummary;
+use transfer::shutdown::Shutdown;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -29,151 +57,616 @@ async fn main() -> Result<(), Box<dyn Error>> {
     tracing::debug!("Command line arguments: {:?}", args);
 
     let config_path = args.config;
-    let goal_config = configuration::get_config::<GoalConfiguration>(config_path).unwrap();
+    let goal_config = configuration::get_config::<GoalConfiguration>(config_path.clone()).unwrap();
     tracing::debug!("goal configuration: {:?}", goal_config);
 
-    let bind_address: SocketAddr = goal_config.server.bind_address();
+    // 重新加载配置时沿用
+    let resolvers = Resolvers::new();
+    let shutdown = Shutdown::new();
+    let sessions = Arc::new(Sessions::with_cancel(shutdown.token()));
+    let limits = Arc::new(Limits::new(&goal_config.limits));
+    let bans = Arc::new(Bans::new(goal_config.ban.as_ref()));
+    let mut drain_timeout = Duration::from_secs(goal_config.shutdown.drain_timeout_secs);
+    let access_log = goal_config.access_log.as_ref().map(|access_log_config| {
+        AccessLog::open(access_log_config).expect("访问日志打开失败 Failed to open access log")
+    });
+
+    let mut state = Arc::new(
+        build_state(&goal_config, None, &resolvers, &sessions, &limits, &bans, &access_log).expect("配置错误 Invalid configuration")
+    );
+    let mut persist_handle = spawn_persist(&state);
 
     // 可能的错误提示：
     // 监听端口失败: Os { code: 48, kind: AddrInUse, message: "Address already in use" }
-    let listener = TcpListener::bind(&bind_address)
-        .await
+    let mut listeners = goal_config.server.listeners()
+        .and_then(|listener_configs| Listener::bind_all(&listener_configs, &[], &state.endpoints))
         .expect("监听失败 Failed to bind");
-    tracing::info!("Listening on: {}, pid: {}", bind_address, std::process::id());
+    for listener in &listeners {
+        tracing::info!("Listening on: {}, tls: {}, pid: {}", listener.address, listener.is_tls(), std::process::id());
+    }
 
-    let dns_configuration = goal_config.dns;
-    let domain_name_handle = domain_name_actor::actor::ActorHandle::new(dns_configuration.server_address);
+    if let Some(admin_config) = &goal_config.admin {
+        let admin_listener = Admin::bind(admin_config.bind_address)
+            .await
+            .expect("管理接口监听失败 Failed to bind admin address");
+        tracing::info!("Admin listening on: {}", admin_config.bind_address);
+        let admin = Arc::new(Admin::new(sessions.clone(), resolvers.clone(), bans.clone()));
+        tokio::spawn(admin.serve(admin_listener));
+    }
+
+    if let Some(metrics_config) = &goal_config.metrics {
+        let metrics_listener = telemetry::bind(metrics_config.bind_address)
+            .await
+            .expect("指标接口监听失败 Failed to bind metrics address");
+        tracing::info!("Metrics listening on: {}", metrics_config.bind_address);
+        tokio::spawn(telemetry::serve(metrics_listener));
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let terminate = transfer::shutdown::terminate_signal()?;
+    tokio::pin!(terminate);
 
     loop {
-        let (soccer_socket, soccer_addr) = match transfer::tcp_accept::tcp_accept(&listener).await {
-            Some(conn) => conn,
-            None => continue,
-        };
+        tokio::select! {
+            (conn, index, _) = futures::future::select_all(listeners.iter().map(|l| Box::pin(l.accept()))) => {
+                let (soccer_socket, soccer_addr) = match conn {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+
+                let listener = &listeners[index];
+                metrics::CONNECTIONS_ACCEPTED.inc();
+                // 不读取请求，直接关闭连接
+                if state.bans.is_banned(soccer_addr.ip()) {
+                    debug!("Closed a connection from banned {} on {}", soccer_addr, listener.address);
+                    metrics::CONNECTIONS_BANNED.inc();
+                    continue;
+                }
+                info!("Accept a connection from {} on {}", soccer_addr, listener.address);
+                shutdown.spawn(process(soccer_socket, soccer_addr, listener.policy.clone(), state.clone()));
+            }
+            _ = &mut terminate => break,
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading {:?}", config_path);
+                let goal_config = match configuration::get_config::<GoalConfiguration>(config_path.clone()) {
+                    Ok(goal_config) => goal_config,
+                    Err(e) => {
+                        error!("Failed to reload configuration, keep the current one, err: {}", e);
+                        continue;
+                    }
+                };
+                let new_state = match build_state(&goal_config, Some(&state), &resolvers, &sessions, &limits, &bans, &access_log) {
+                    Ok(new_state) => new_state,
+                    Err(e) => {
+                        error!("Invalid configuration, keep the current one, err: {}", e);
+                        continue;
+                    }
+                };
+
+                // 先监听新增的地址，全部成功后才关闭不再使用的监听；已建立的会话不受影响
+                let new_listeners = goal_config.server.listeners()
+                    .and_then(|listener_configs| Listener::bind_all(&listener_configs, &listeners, &new_state.endpoints));
+                match new_listeners {
+                    Ok(new_listeners) => {
+                        for listener in &listeners {
+                            if !new_listeners.iter().any(|l| l.address == listener.address) {
+                                info!("Stopped listening on: {}", listener.address);
+                            }
+                        }
+                        for listener in &new_listeners {
+                            if !listeners.iter().any(|l| l.address == listener.address) {
+                                info!("Listening on: {}, tls: {}", listener.address, listener.is_tls());
+                            }
+                        }
+                        listeners = new_listeners;
+                    }
+                    Err(e) => {
+                        error!("Invalid listeners, keep the current configuration, err: {}", e);
+                        continue;
+                    }
+                }
+
+                // 新的会话使用新的配置，已有的会话继续使用旧的
+                if let Some(handle) = persist_handle.take() {
+                    handle.abort();
+                }
+                save_usage(&state).await;
+                state = Arc::new(new_state);
+                persist_handle = spawn_persist(&state);
+                limits.reconfigure(&goal_config.limits);
+                bans.reconfigure(goal_config.ban.as_ref());
+                drain_timeout = Duration::from_secs(goal_config.shutdown.drain_timeout_secs);
+                info!("Configuration reloaded");
+            }
+        }
+    }
+
+    // 停止接受新连接，等待进行中的隧道结束
+    drop(listeners);
+    shutdown.drain(drain_timeout).await;
+
+    if let Some(handle) = persist_handle {
+        handle.abort();
+    }
+    save_usage(&state).await;
+    if let Some(access_log) = &access_log {
+        access_log.close();
+    }
+    info!("Exited");
+    Ok(())
+}
+
+// 按配置构建所有会话共享的状态。
+// 重新加载配置时传入当前的状态，沿用其中已使用的 nonce、各用户的隧道数与流量
+fn build_state(
+    goal_config: &GoalConfiguration,
+    previous: Option<&GoalState>,
+    resolvers: &Resolvers,
+    sessions: &Arc<Sessions>,
+    limits: &Arc<Limits>,
+    bans: &Arc<Bans>,
+    access_log: &Option<AccessLog>,
+) -> Result<GoalState, String> {
+    let endpoints = Endpoints::new(goal_config, resolvers)
+        .map_err(|e| format!("invalid endpoints, {}", e))?;
+
+    let (authenticator, accounts) = match &goal_config.auth {
+        Some(auth_config) => {
+            let now = auth_token::unix_timestamp();
+            let previous_auth = previous.and_then(|p| p.authenticator.as_ref().zip(p.accounts.as_ref()));
+            let (authenticator, accounts) = match previous_auth {
+                Some((authenticator, accounts)) => {
+                    (authenticator.reload(auth_config), accounts.reload(auth_config, now)?)
+                }
+                None => {
+                    let authenticator = Authenticator::new(auth_config)
+                        .map_err(|e| format!("failed to load nonce journal, {}", e))?;
+                    (authenticator, Accounts::new(auth_config, now)?)
+                }
+            };
+            (Some(authenticator), Some(Arc::new(accounts)))
+        }
+        None => (None, None),
+    };
+
+    let connector = Connector::new(goal_config)
+        .map_err(|e| format!("invalid outbound, {}", e))?;
+
+    let relay_config = relay_config(&goal_config.tunnel);
+    relay_config.read_buffer.validate()
+        .map_err(|e| format!("invalid tunnel, {}", e))?;
+    let websocket_limits = websocket_limits(&goal_config.websocket);
+    websocket_limits.validate()
+        .map_err(|e| format!("invalid websocket, {}", e))?;
+
+    Ok(GoalState {
+        endpoints,
+        authenticator,
+        accounts,
+        fallback: Fallback::new(goal_config.fallback.as_ref()),
+        connector,
+        relay_config,
+        websocket_limits,
+        upgrade_timeout: Duration::from_secs(goal_config.handshake.upgrade_timeout_secs),
+        request_header_timeout: Duration::from_secs(goal_config.handshake.request_header_timeout_secs),
+        sessions: sessions.clone(),
+        limits: limits.clone(),
+        bans: bans.clone(),
+        access_log: access_log.clone(),
+    })
+}
+
+// 在 goal 中，TCP 连接是目标，WebSocket 是 soccer
+fn relay_config(tunnel_config: &TunnelConfiguration) -> RelayConfig {
+    let default = ReadBufferSize::default();
+    RelayConfig {
+        read_buffer: ReadBufferSize {
+            min: tunnel_config.min_read_buffer_size.unwrap_or(default.min),
+            max: tunnel_config.max_read_buffer_size.unwrap_or(default.max),
+        },
+        tcp_idle_timeout: tunnel_config.download_idle_timeout_secs.map(Duration::from_secs),
+        ws_idle_timeout: tunnel_config.upload_idle_timeout_secs.map(Duration::from_secs),
+        ping_interval: tunnel_config.ping_interval_secs.map(Duration::from_secs),
+        pong_timeout: tunnel_config.pong_timeout_secs.map(Duration::from_secs),
+        keepalive_interval: tunnel_config.keepalive_interval_secs.map(Duration::from_secs),
+        max_lifetime: tunnel_config.max_lifetime_secs.map(Duration::from_secs),
+    }
+}
+
+fn websocket_limits(websocket_config: &WebSocketConfiguration) -> WebSocketLimits {
+    WebSocketLimits {
+        max_frame_size: websocket_config.max_frame_size,
+        max_message_size: websocket_config.max_message_size,
+        write_buffer_size: websocket_config.write_buffer_size,
+        max_write_buffer_size: websocket_config.max_write_buffer_size,
+    }
+}
+
+fn spawn_persist(state: &GoalState) -> Option<tokio::task::JoinHandle<()>> {
+    state.accounts.as_ref()?.spawn_persist(Duration::from_secs(30))
+}
+
+async fn save_usage(state: &GoalState) {
+    if let Some(accounts) = &state.accounts {
+        accounts.clone().save_usage_blocking().await;
+    }
+}
+
+type SoccerStream = PrefixedStream<ClientStream>;
+
+// 所有会话共享的状态
+struct GoalState {
+    endpoints: Endpoints,
+    authenticator: Option<Authenticator>,
+    accounts: Option<Arc<Accounts>>,
+    fallback: Fallback,
+    connector: Connector,
+    relay_config: RelayConfig,
+    websocket_limits: WebSocketLimits,
+    upgrade_timeout: Duration,
+    request_header_timeout: Duration,
+    sessions: Arc<Sessions>,
+    limits: Arc<Limits>,
+    bans: Arc<Bans>,
+    access_log: Option<AccessLog>,
+}
+
+async fn process(mut soccer_socket: ClientStream, peer_addr: SocketAddr, policy: ListenerPolicy, state: Arc<GoalState>) {
+    // 从接受连接开始计算
+    let upgrade_deadline = Instant::now() + state.upgrade_timeout;
+
+    let mut soccer_addr = peer_addr;
+    if policy.proxy_protocol && !policy.trusted_proxies.is_empty() && !policy.trusted_proxies.contains(peer_addr.ip()) {
+        tracing::warn!("Rejected connection from {}, not a trusted proxy", peer_addr);
+        metrics::handshake_failed("untrusted_proxy");
+        return;
+    }
+
+    // 读取任何数据之前先占用名额，连接结束时归还。
+    // 经 PROXY protocol 或受信任的代理连接时，此时还不知道客户端的地址，之后再按 IP 计数
+    let forwarded = policy.trusted_proxies.contains(peer_addr.ip());
+    let client_ip = if policy.proxy_protocol || forwarded { None } else { Some(peer_addr.ip()) };
+    let mut permit = match state.limits.admit(client_ip).await {
+        Ok(permit) => permit,
+        Err(e) => {
+            tracing::warn!("Rejected connection from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed(e.reason());
+            // 启用 TLS 时不再握手，直接关闭连接
+            if !policy.is_tls() {
+                let _ = limits::reject(&mut soccer_socket).await;
+            }
+            return;
+        }
+    };
+
+    if policy.proxy_protocol {
+        match before(upgrade_deadline, read_proxy_header(&mut soccer_socket)).await {
+            Ok(Some(client_addr)) => {
+                debug!("Connection from {} via proxy {}", client_addr, peer_addr);
+                if state.bans.is_banned(client_addr.ip()) {
+                    debug!("Closed a connection from banned {}", client_addr);
+                    metrics::CONNECTIONS_BANNED.inc();
+                    return;
+                }
+                soccer_addr = client_addr;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                info!("Invalid PROXY protocol header from {}, err: {}", peer_addr, e);
+                metrics::handshake_failed(timeout_or(&e, "proxy_protocol"));
+                return;
+            }
+        }
+    }
+
+    // 来自受信任代理的连接，读到请求头之后才知道客户端的地址
+    let forwarded = policy.trusted_proxies.contains(soccer_addr.ip());
+    if client_ip.is_none() && !forwarded {
+        if let Err(e) = permit.set_client_ip(soccer_addr.ip()) {
+            tracing::warn!("Rejected connection from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed(e.reason());
+            if !policy.is_tls() {
+                let _ = limits::reject(&mut soccer_socket).await;
+            }
+            return;
+        }
+    }
+
+    let mut soccer_socket = match before(upgrade_deadline, policy.handshake(soccer_socket)).await {
+        Ok(soccer_socket) => soccer_socket,
+        Err(e) => {
+            info!("TLS handshake with {} failed, err: {}", soccer_addr, e);
+            metrics::handshake_failed(timeout_or(&e, "tls"));
+            return;
+        }
+    };
+
+    let mut head_buf = BytesMut::with_capacity(1024);
+    let head_ret = before(upgrade_deadline, read_request_head(&mut soccer_socket, &mut head_buf)).await;
+    let soccer_stream = PrefixedStream::new(head_buf.freeze(), soccer_socket);
+
+    let head = match head_ret {
+        Ok(head) => head,
+        Err(e) => {
+            info!("Invalid request from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed(timeout_or(&e, "invalid_request"));
+            if e.kind() != io::ErrorKind::UnexpectedEof && e.kind() != io::ErrorKind::TimedOut {
+                record_failure(soccer_addr, &policy, &state);
+                permit.handshake_done();
+                serve_fallback(soccer_stream, None, soccer_addr, &state).await;
+            }
+            return;
+        }
+    };
+
+    if forwarded {
+        let client_addr = policy.trusted_proxies.client_addr(soccer_addr, &head);
+        if client_addr != soccer_addr {
+            debug!("Connection from {} via proxy {}", client_addr, soccer_addr);
+        }
+        soccer_addr = client_addr;
+        if state.bans.is_banned(soccer_addr.ip()) {
+            debug!("Closed a connection from banned {}", soccer_addr);
+            metrics::CONNECTIONS_BANNED.inc();
+            return;
+        }
+        if let Err(e) = permit.set_client_ip(soccer_addr.ip()) {
+            tracing::warn!("Rejected connection from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed(e.reason());
+            let mut soccer_stream = soccer_stream;
+            let _ = limits::reject(&mut soccer_stream).await;
+            return;
+        }
+    }
+
+    let (endpoint, lease) = match route(&head, soccer_addr, &policy, &state) {
+        Route::Tunnel(endpoint, lease) => (endpoint, lease),
+        Route::Fallback => {
+            // 普通的 Web 请求不算失败
+            if head.is_websocket_upgrade() {
+                record_failure(soccer_addr, &policy, &state);
+            }
+            permit.handshake_done();
+            serve_fallback(soccer_stream, Some(&head), soccer_addr, &state).await;
+            return;
+        }
+        Route::Reject(status) => {
+            let mut soccer_stream = soccer_stream;
+            let _ = write_response(&mut soccer_stream, &head, status, "text/plain", b"").await;
+            return;
+        }
+    };
+    let user = lease.as_ref().map(|lease| lease.account().name().to_string());
+    if let Some(user) = &user {
+        info!("Authenticated user '{}' from {} on '{}'", user, soccer_addr, endpoint.path);
+    }
+
+    let session = state.sessions.register(soccer_addr, user, endpoint.path.clone());
+    let mut record = AccessRecord::new(&session);
+    let active_tunnel = metrics::ActiveTunnel::start();
+    let established = tokio::select! {
+        established = establish(soccer_stream, upgrade_deadline, &endpoint, &session, &state, &mut record) => established,
+        _ = session.killed() => {
+            info!("Session {} from {} killed", session.id, soccer_addr);
+            Err("stopped")
+        }
+    };
+    let outcome = match established {
+        Ok((ws, dest_stream, early_data)) => {
+            permit.handshake_done();
+            match relay(ws, dest_stream, early_data, lease.as_ref(), &session, &state.relay_config).await {
+                Ok(summary) => {
+                    record.set_ends(&summary);
+                    relay_outcome(&summary)
+                }
+                Err(reason) => reason,
+            }
+        }
+        Err(reason) => reason,
+    };
+    drop(active_tunnel);
+    metrics::SESSION_DURATION.observe(session.age().as_secs_f64());
 
-        info!("Accept a connection from {}", soccer_addr);
-        tokio::spawn(process(soccer_socket, domain_name_handle.clone()));
+    if let Some(access_log) = &state.access_log {
+        record.finish(outcome, &session);
+        access_log.log(&record);
     }
+
+    // 隧道结束后才归还名额
+    drop(lease);
 }
 
-async fn process(soccer_socket: TcpStream, domain_name_handle: domain_name_actor::actor::ActorHandle) {
-    let mut ws = match ws_accept(soccer_socket).await {
-        Some(ws_stream) => ws_stream,
-        None => return,
+// 完成 WebSocket 握手、读取协议头并连接目标，返回随请求头发送的首批数据；
+// 失败时返回原因，目标与错误记入 `record`
+async fn establish(
+    soccer_stream: SoccerStream,
+    upgrade_deadline: Instant,
+    endpoint: &Endpoint,
+    session: &Session,
+    state: &GoalState,
+    record: &mut AccessRecord,
+) -> Result<(WebSocketStream<SoccerStream>, TcpStream, Bytes), &'static str> {
+    let soccer_addr = session.client_addr;
+    let mut ws = match tokio::time::timeout_at(upgrade_deadline, ws_accept(soccer_stream, &state.websocket_limits)).await {
+        Ok(Some(ws_stream)) => ws_stream,
+        Ok(None) => {
+            metrics::handshake_failed("websocket");
+            return Err("websocket");
+        }
+        Err(_) => {
+            info!("WebSocket upgrade from {} timed out", soccer_addr);
+            metrics::handshake_failed("upgrade_timeout");
+            return Err("upgrade_timeout");
+        }
     };
 
     // 读协议头
     // TODO: How to handle `Option` and `Result`
-    let request_header_msg_ret = ws.next().await;
+    let request_header_msg_ret = match tokio::time::timeout(state.request_header_timeout, ws.next()).await {
+        Ok(ret) => ret,
+        Err(_) => {
+            info!("Timed out waiting for request header from {}", soccer_addr);
+            metrics::handshake_failed("request_header_timeout");
+            return Err("request_header_timeout");
+        }
+    };
     if request_header_msg_ret.is_none() {
         error!("Not received request header message");
-        return;
+        metrics::handshake_failed("request_header");
+        return Err("request_header");
     }
     let msg_ret = request_header_msg_ret.unwrap();
+    if let Err(WsError::Capacity(e)) = &msg_ret {
+        info!("Oversized request header from {}, err: {}", soccer_addr, e);
+        metrics::handshake_failed("message_too_big");
+        let _ = ws.send(Message::Close(Teardown::MessageTooBig.close_frame())).await;
+        return Err("message_too_big");
+    }
     if msg_ret.is_err() {
         error!("Failed to receive request message");
-        return;
+        metrics::handshake_failed("request_header");
+        return Err("request_header");
     }
 
     let request_header_msg = msg_ret.unwrap();
-    println!("request_header_msg: {:?}", request_header_msg);
+    debug!("request_header_msg: {:?}", request_header_msg);
     if !request_header_msg.is_binary() {
-        return;
+        metrics::handshake_failed("request_header");
+        return Err("request_header");
     }
-    let request_header = request_header_msg.into_data();
     // 解析目标域名和目标端口
-    let (dest_domain, dest_port) = decode_request_header(&request_header).unwrap();
-
-    let dest_ip_addr_ret = domain_name_handle.query(NameQuery::a_record(dest_domain.as_str())).await;
-
-    if dest_ip_addr_ret.is_none() {
-        // TODO DNS 没有查找到不属于协议错误，应该用其他方式告知客户端
-        // "Could not resolve host"
-        return;
+    let request_header = match Frame::decode(Bytes::from(request_header_msg.into_data())) {
+        Ok(Frame::Connect(request_header)) => request_header,
+        Err(e @ DecodeError::UnsupportedVersion(_)) => {
+            info!("Rejected request header from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed("protocol_version");
+            let close_frame = CloseFrame { code: CloseCode::Protocol, reason: e.to_string().into() };
+            let _ = ws.send(Message::Close(Some(close_frame))).await;
+            return Err("protocol_version");
+        }
+        other => {
+            error!("Invalid request header from {}: {:?}", soccer_addr, other);
+            metrics::handshake_failed("request_header");
+            return Err("request_header");
+        }
+    };
+    debug!("Request header: {:?}", request_header);
+    let (dest_domain, dest_port) = (request_header.address.to_string(), request_header.port);
+    record.domain = Some(dest_domain.clone());
+    record.port = Some(dest_port);
+    if request_header.flags.udp {
+        info!("Rejected UDP request from {} to {}:{}", soccer_addr, dest_domain, dest_port);
+        metrics::handshake_failed("udp_not_supported");
+        send_reply(&mut ws, ConnectReply::CommandNotSupported).await;
+        record.error = Some("UDP is not supported".to_string());
+        return Err("udp_not_supported");
     }
-    let dest_ip_addr = dest_ip_addr_ret.unwrap();
 
-    let dest_addr = SocketAddr::new(dest_ip_addr, dest_port);
-    println!("Resolved dest_addr: {}", dest_addr);
+    let user = session.user.as_deref();
+    let connect_started_at = std::time::Instant::now();
+    let family = request_header.flags.dns_family;
+    let connect_ret = state.connector.connect(endpoint, user, &dest_domain, dest_port, family).await;
+    let connect_result = if connect_ret.is_ok() { "ok" } else { "error" };
+    metrics::CONNECT_DURATION
+        .with_label_values(&[connect_result])
+        .observe(connect_started_at.elapsed().as_secs_f64());
+
+    let connected = match connect_ret {
+        Ok(conn) => conn,
+        Err(e) => {
+            metrics::handshake_failed(e.reason());
+            tracing::warn!("Failed to connect from {} to {}:{}, err: {}", soccer_addr, dest_domain, dest_port, e);
+            send_reply(&mut ws, e.reply()).await;
+            record.error = Some(e.to_string());
+            return Err(e.reason());
+        }
+    };
+    record.resolved_addr = connected.dest_addr.map(|addr| addr.to_string());
+    record.upstream = connected.upstream.clone();
+    debug!("Connected to {} ({}) for {}", connected, dest_domain, soccer_addr);
+    session.set_destination(format!("{}:{} ({})", dest_domain, dest_port, connected));
+    if request_header.flags.no_delay {
+        if let Err(e) = connected.stream.set_nodelay(true) {
+            debug!("Failed to set TCP_NODELAY, err: {}", e);
+        }
+    }
+    if !send_reply(&mut ws, ConnectReply::Succeeded).await {
+        return Err("reply");
+    }
 
-    let dest_stream = TcpStream::connect(dest_addr)
-        .await
-        .expect("Failed to connect to destination");
+    Ok((ws, connected.stream, request_header.early_data))
+}
 
+// 先把首批数据写入目标，再在 soccer 与目标之间转发数据，直到两个方向都结束或会话被结束。
+// 流量记入会话的 `counters`，管理接口可以实时查看；写入首批数据失败时返回原因
+async fn relay(
+    mut ws: WebSocketStream<SoccerStream>,
+    dest_stream: TcpStream,
+    early_data: Bytes,
+    lease: Option<&TunnelLease>,
+    session: &Session,
+    relay_config: &RelayConfig,
+) -> Result<RelaySummary, &'static str> {
     let (dest_read, dest_write) = dest_stream.into_split();
-    let (soccer_write, soccer_read) = ws.split();
+    let account = lease.map(|l| l.account().clone());
 
     // dest ==> soccer
-    let dest_to_soccer = tokio::spawn(async move {
-        transfer::tcp_to_ws(dest_read, soccer_write).await;
-    });
+    let dest_read = MeteredRead::new(dest_read, account.clone(), Direction::Download);
 
     // soccer ===> dest
-    let soccer_to_dest = tokio::spawn(async move {
-        transfer::ws_to_tcp(soccer_read, dest_write).await;
-    });
+    let mut dest_write = MeteredWrite::new(dest_write, account, Direction::Upload);
+    if !early_data.is_empty() {
+        if let Err(e) = dest_write.write_all(&early_data).await {
+            info!("Failed to write early data for session {}, err: {}", session.id, e);
+            // 通知 soccer 目标的连接已重置
+            let reset = ws.send(Message::binary(Frame::Reset.encode()));
+            if let Err(e) = tokio::time::timeout(transfer::shutdown::CLOSE_TIMEOUT, reset).await.unwrap_or(Ok(())) {
+                error!("Failed to send Reset, err: {:?}", e);
+            }
+            return Err("early_data_failed");
+        }
+        session.counters.ws_to_tcp.add(early_data.len());
+    }
+
+    // 在同一个任务中完成两个方向的转发，超时或会话被结束时向 soccer 发送 Close 帧
+    let (soccer_write, soccer_read) = ws.split();
+    let summary = transfer::relay(dest_read, dest_write, soccer_read, soccer_write, relay_config, &session.counters, session.killed()).await;
+    info!("Session {} from {} ended, download: {}, upload: {}",
+          session.id, session.client_addr, summary.tcp_to_ws, summary.ws_to_tcp);
+    Ok(summary)
+}
 
-    let _ = tokio::join!(dest_to_soccer, soccer_to_dest);
-}
-
-fn decode_request_header(request_header: &[u8]) -> std::io::Result<(String, u16)> {
-    let mut buf = BytesMut::from(request_header);
-
-    let port = Buf::get_u16(&mut buf);
-    println!("port: {}", port);
-
-    let _reserved = Buf::get_u16(&mut buf);
-    println!("reserved: {}", _reserved);
-
-    let len = Buf::get_u16(&mut buf) as usize;
-    println!("len: {}", len);
-
-    // 如果使用 `Vec<u8>` 作为缓冲区，则需要分配并初始化内存，
-    // 而使用 `BytesMut` 可以避免
-    let domain_bytes = BytesMut::split_to(&mut buf, len);
-    let domain = String::from_utf8_lossy(&domain_bytes).to_string();
-    println!("domain: {}", domain);
-
-    // if buf.has_remaining() {
-    //     // buf should be empty
-    // }
-
-    Ok((domain, port))
-}
-
-async fn ws_accept(tcp_stream: TcpStream) -> Option<WebSocketStream<TcpStream>> {
-    use http::Response as HttpResponse;
-    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
-
-    let callback = |req: &Request, resp: Response| {
-        // req 示例:
-        // Request { method: GET, uri: /goal, version: HTTP/1.1,
-        //     headers: {
-        //         "host": "127.0.0.1:18030",
-        //         "connection": "Upgrade",
-        //         "upgrade": "websocket",
-        //         "sec-websocket-version": "13",
-        //         "sec-websocket-key": "4qua2jXK9gwaSWDVWZZ8Ow=="
-        //     },
-        //     body: ()
-        // }
-        let path = req.uri().path();
-        if path == "/goal" {
-            Ok(resp)
-        } else {
-            // https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
-            // https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.2
-            //
-            // Any status code other than 101 indicates that the WebSocket handshake
-            // has not completed and that the semantics of HTTP still apply.
-            let err_resp = HttpResponse::builder()
-                .status(404)
-                .body(None)
-                .unwrap();
-            Err(err_resp)
+// 提前结束时为结束的原因，某个方向读写出错时为出错的方向
+fn relay_outcome(summary: &RelaySummary) -> &'static str {
+    if let Some(teardown) = summary.teardown {
+        return teardown.as_str();
+    }
+    if !summary.ws_to_tcp.end.is_clean() {
+        return "upload_error";
+    }
+    if !summary.tcp_to_ws.end.is_clean() {
+        return "download_error";
+    }
+    "ok"
+}
+
+// 把连接目标的结果告知 soccer，返回是否发送成功
+async fn send_reply(ws: &mut WebSocketStream<SoccerStream>, reply: ConnectReply) -> bool {
+    match ws.send(Message::binary(Frame::ConnectResult(reply).encode())).await {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Failed to send reply {:?}, err: {:?}", reply, e);
+            false
         }
-    };
+    }
+}
 
-    match tokio_tungstenite::accept_hdr_async(tcp_stream, callback).await {
+async fn ws_accept<S>(soccer_stream: S, limits: &WebSocketLimits) -> Option<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 请求头已经检查过，这里由 tungstenite 重新解析并完成握手
+    match tokio_tungstenite::accept_async_with_config(soccer_stream, Some(limits.to_config())).await {
         Ok(ws_stream) => {
             Some(ws_stream)
         }
@@ -184,20 +677,105 @@ async fn ws_accept(tcp_stream: TcpStream) -> Option<WebSocketStream<TcpStream>>
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// 超过 WebSocket 升级的期限时返回 `TimedOut`
+async fn before<T>(deadline: Instant, f: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match tokio::time::timeout_at(deadline, f).await {
+        Ok(ret) => ret,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "upgrade timed out")),
+    }
+}
+
+// 用作指标的标签
+fn timeout_or(e: &io::Error, reason: &'static str) -> &'static str {
+    if e.kind() == io::ErrorKind::TimedOut {
+        "upgrade_timeout"
+    } else {
+        reason
+    }
+}
+
+// 记录握手失败，多次失败后封禁来源 IP。
+// 经受信任的代理连接却没有得到客户端的地址时不记录，以免封禁代理
+fn record_failure(soccer_addr: SocketAddr, policy: &ListenerPolicy, state: &GoalState) {
+    if policy.trusted_proxies.contains(soccer_addr.ip()) {
+        return;
+    }
+    if let Some(ban) = state.bans.record_failure(soccer_addr.ip()) {
+        tracing::warn!("Banned {} for {}s after repeated handshake failures", soccer_addr.ip(), ban.as_secs());
+    }
+}
+
+async fn serve_fallback(
+    soccer_stream: SoccerStream,
+    head: Option<&RequestHead>,
+    soccer_addr: SocketAddr,
+    state: &GoalState,
+) {
+    if let Some(head) = head {
+        debug!("Fallback for {} {} from {}", head.method, head.target, soccer_addr);
+    }
+    if let Err(e) = state.fallback.serve(soccer_stream, head).await {
+        debug!("Fallback for {} failed, err: {}", soccer_addr, e);
+    }
+}
+
+enum Route {
+    // 建立隧道，启用认证时带有用户的隧道名额
+    Tunnel(Arc<Endpoint>, Option<TunnelLease>),
+    // 交给回落处理
+    Fallback,
+    // 已认证的用户被拒绝，返回 HTTP 状态码
+    Reject(u16),
+}
 
-    #[test]
-    fn decode_request_header_test() {
-        let request_header_data = vec![
-            0, 80, // port 80
-            0, 0,
-            0x0, 0x4, 122, 46, 99, 110, // "z.cn"
-        ];
+// req 示例:
+// GET /goal HTTP/1.1
+// host: 127.0.0.1:18030
+// connection: Upgrade
+// upgrade: websocket
+// sec-websocket-version: 13
+// sec-websocket-key: 4qua2jXK9gwaSWDVWZZ8Ow==
+fn route(head: &RequestHead, soccer_addr: SocketAddr, policy: &ListenerPolicy, state: &GoalState) -> Route {
+    let endpoint = match state.endpoints.get(head.path()) {
+        Some(endpoint) if head.is_websocket_upgrade() && policy.is_path_allowed(head.path()) => endpoint.clone(),
+        _ => return Route::Fallback,
+    };
 
-        let (domain, port) = decode_request_header(&request_header_data).unwrap();
-        assert_eq!(domain, "z.cn");
-        assert_eq!(port, 80);
+    let authenticator = match &state.authenticator {
+        Some(authenticator) => authenticator,
+        None => return Route::Tunnel(endpoint, None),
+    };
+
+    let user = match authenticator.authenticate(head.header(auth_token::HEADER_NAME)) {
+        Ok(user) => user,
+        Err(e) => {
+            // 与普通的 Web 请求同样处理，不暴露 goal 的存在
+            tracing::warn!("Rejected handshake from {}, err: {}", soccer_addr, e);
+            metrics::handshake_failed("unauthorized");
+            return Route::Fallback;
+        }
+    };
+
+    if !endpoint.is_user_allowed(Some(&user)) {
+        tracing::warn!("Rejected user '{}' from {} on '{}', user not allowed", user, soccer_addr, endpoint.path);
+        metrics::handshake_failed("user_not_allowed");
+        return Route::Fallback;
+    }
+
+    let account = match state.accounts.as_ref().and_then(|accounts| accounts.get(&user)) {
+        Some(account) => account,
+        None => return Route::Tunnel(endpoint, None),
+    };
+    match account.admit(auth_token::unix_timestamp()) {
+        Ok(lease) => Route::Tunnel(endpoint, Some(lease)),
+        Err(e) => {
+            tracing::warn!("Rejected user '{}' from {}, err: {}", user, soccer_addr, e);
+            metrics::handshake_failed("admission");
+            match e {
+                AdmissionError::TooManyTunnels => Route::Reject(429),
+                AdmissionError::Expired | AdmissionError::QuotaExceeded => Route::Reject(403),
+            }
+        }
     }
 }
+
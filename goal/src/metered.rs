@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use crate::accounts::Account;
+use crate::rate_limit::RateLimiter;
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    // soccer ==> 目标
+    Upload,
+    // 目标 ==> soccer
+    Download,
+}
+
+// 有用户时按用户限速，超出本月配额后返回错误，从而结束隧道。
+// 流量由 `transfer` 记入会话的 `RelayCounters`
+struct Meter {
+    account: Option<Arc<Account>>,
+    direction: Direction,
+    over_quota: bool,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Meter {
+    fn new(account: Option<Arc<Account>>, direction: Direction) -> Self {
+        Self {
+            account,
+            direction,
+            over_quota: false,
+            sleep: None,
+        }
+    }
+
+    fn limiter(&self) -> Option<&RateLimiter> {
+        let account = self.account.as_ref()?;
+        match self.direction {
+            Direction::Upload => account.upload_limiter(),
+            Direction::Download => account.download_limiter(),
+        }
+    }
+
+    // 等待限速结束
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.over_quota {
+            let e = io::Error::new(io::ErrorKind::PermissionDenied, "monthly quota exceeded");
+            return Poll::Ready(Err(e));
+        }
+
+        if let Some(sleep) = self.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn record(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let account = match &self.account {
+            Some(account) => account,
+            None => return,
+        };
+        self.over_quota = account.add_usage(n as u64, auth_token::unix_timestamp());
+
+        let wait = self.limiter().map(|l| l.consume(n)).unwrap_or(Duration::ZERO);
+        if !wait.is_zero() {
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+pub struct MeteredRead<R> {
+    inner: R,
+    meter: Meter,
+}
+
+impl<R> MeteredRead<R> {
+    pub fn new(inner: R, account: Option<Arc<Account>>, direction: Direction) -> Self {
+        Self {
+            inner,
+            meter: Meter::new(account, direction),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredRead<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        futures::ready!(this.meter.poll_ready(cx))?;
+
+        let before = buf.filled().len();
+        futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.meter.record(buf.filled().len() - before);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct MeteredWrite<W> {
+    inner: W,
+    meter: Meter,
+}
+
+impl<W> MeteredWrite<W> {
+    pub fn new(inner: W, account: Option<Arc<Account>>, direction: Direction) -> Self {
+        Self {
+            inner,
+            meter: Meter::new(account, direction),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for MeteredWrite<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        futures::ready!(this.meter.poll_ready(cx))?;
+
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.meter.record(n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,123 @@
+// 连接目标时使用的出口：源地址、网络接口与 fwmark
+//
+// 可以全局配置，也可以按 WebSocket 路径或按用户覆盖，优先级：用户 > 路径 > 全局
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, TcpStream};
+use configuration::OutboundConfiguration;
+
+#[derive(Debug, Default)]
+pub struct OutboundPolicy {
+    source_v4: Vec<IpAddr>,
+    source_v6: Vec<IpAddr>,
+    // 轮流使用源地址池中的地址
+    next_source: AtomicUsize,
+    interface: Option<String>,
+    mark: Option<u32>,
+}
+
+impl OutboundPolicy {
+    pub fn new(outbound_config: &OutboundConfiguration) -> Result<Self, String> {
+        if cfg!(not(target_os = "linux")) && (outbound_config.interface.is_some() || outbound_config.mark.is_some()) {
+            return Err("outbound interface and mark are only supported on Linux".to_string());
+        }
+        if let Some(interface) = &outbound_config.interface {
+            if interface.is_empty() {
+                return Err("outbound interface must not be empty".to_string());
+            }
+        }
+
+        let (source_v4, source_v6) = outbound_config.source_addresses.iter()
+            .partition(|ip| ip.is_ipv4());
+
+        Ok(Self {
+            source_v4,
+            source_v6,
+            next_source: AtomicUsize::new(0),
+            interface: outbound_config.interface.clone(),
+            mark: outbound_config.mark,
+        })
+    }
+
+    // 为目标选择源地址，源地址池为空时返回 `Ok(None)`，由系统选择
+    pub fn source_address(&self, dest_addr: SocketAddr) -> io::Result<Option<IpAddr>> {
+        if self.source_v4.is_empty() && self.source_v6.is_empty() {
+            return Ok(None);
+        }
+
+        let pool = if dest_addr.is_ipv4() { &self.source_v4 } else { &self.source_v6 };
+        if pool.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no source address for {}", dest_addr),
+            ));
+        }
+
+        let i = self.next_source.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(pool[i % pool.len()]))
+    }
+
+    pub async fn connect(&self, dest_addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if dest_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(interface) = &self.interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+            if let Some(mark) = self.mark {
+                socket2::SockRef::from(&socket).set_mark(mark)?;
+            }
+        }
+
+        if let Some(source_ip) = self.source_address(dest_addr)? {
+            socket.bind(SocketAddr::new(source_ip, 0))?;
+        }
+
+        socket.connect(dest_addr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn outbound_config(source_addresses: &[&str]) -> OutboundConfiguration {
+        OutboundConfiguration {
+            source_addresses: source_addresses.iter().map(|s| s.parse().unwrap()).collect(),
+            interface: None,
+            mark: None,
+        }
+    }
+
+    #[test]
+    fn source_address_test() {
+        let dest_v4: SocketAddr = "203.0.113.1:443".parse().unwrap();
+        let dest_v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        let policy = OutboundPolicy::default();
+        assert_eq!(None, policy.source_address(dest_v4).unwrap());
+
+        let policy = OutboundPolicy::new(&outbound_config(&["198.51.100.10", "198.51.100.11"])).unwrap();
+        let first = policy.source_address(dest_v4).unwrap().unwrap();
+        let second = policy.source_address(dest_v4).unwrap().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first, policy.source_address(dest_v4).unwrap().unwrap());
+        assert_eq!(io::ErrorKind::AddrNotAvailable, policy.source_address(dest_v6).unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn connect_from_source_address_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = listener.local_addr().unwrap();
+
+        let policy = OutboundPolicy::new(&outbound_config(&["127.0.0.2"])).unwrap();
+        let _stream = policy.connect(dest_addr).await.unwrap();
+
+        let (_, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!("127.0.0.2".parse::<IpAddr>().unwrap(), peer_addr.ip());
+    }
+}
@@ -0,0 +1,163 @@
+// PROXY protocol v1 与 v2 的头部，由 HAProxy、云负载均衡等在连接开始时发送客户端的地址
+//
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// v1 头部的最大长度，包括末尾的 "\r\n"
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// 读取并解析 PROXY protocol 头部，不会读取头部之后的数据。
+// 返回头部中的源地址，负载均衡的健康检查（v2 的 LOCAL、v1 的 UNKNOWN）等没有源地址时返回 `None`
+pub async fn read_proxy_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // 两个版本的头部都不短于 12 字节
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix.starts_with(V1_PREFIX) {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if &prefix == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(fixed[0], fixed[1], &body)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match the family"));
+            }
+            let port: u16 = src_port.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY v1 header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    // 高 4 位为地址族，低 4 位为传输协议；其后的 TLV 忽略
+    match family >> 4 {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC、AF_UNIX
+        0 | 3 => Ok(None),
+        _ => Err(invalid("invalid PROXY v2 address")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = data;
+        let ret = read_proxy_header(&mut stream).await;
+        (ret, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_test() {
+        let (ret, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), ret.unwrap());
+        assert_eq!(b"GET / HTTP/1.1\r\n", rest.as_slice());
+
+        let (ret, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), ret.unwrap());
+
+        let (ret, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(None, ret.unwrap());
+
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").await.0.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").await.0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_test() {
+        let mut data = V2_SIGNATURE.to_vec();
+        // PROXY，TCP over IPv4，地址 12 字节与 3 字节的 TLV
+        data.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        data.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        data.extend_from_slice(&[0x04, 0, 0]);
+        data.extend_from_slice(b"GET");
+        let (ret, rest) = read(&data).await;
+        assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), ret.unwrap());
+        assert_eq!(b"GET", rest.as_slice());
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&[0u8; 16]);
+        data.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), read(&data).await.0.unwrap());
+
+        // LOCAL
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(None, read(&data).await.0.unwrap());
+
+        // 地址不完整
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(read(&data).await.0.is_err());
+
+        // 版本错误
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(read(&data).await.0.is_err());
+    }
+}
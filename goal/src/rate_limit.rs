@@ -0,0 +1,76 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// 令牌桶，同一用户的所有隧道共享。
+//
+// 先传输再扣减令牌，令牌不足时允许欠账，由调用者等待返回的时长，
+// 这样长期的平均速率不会超过 `rate`。
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    // `rate`：每秒字节数，突发容量为一秒的量
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Self {
+            rate,
+            burst: rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    // 消耗 `n` 个令牌，返回需要等待的时长
+    pub fn consume(&self, n: usize) -> Duration {
+        self.consume_at(n, Instant::now())
+    }
+
+    fn consume_at(&self, n: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+
+        bucket.tokens -= n as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_test() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+
+        assert_eq!(Duration::ZERO, limiter.consume_at(1000, now));
+        assert_eq!(Duration::from_millis(500), limiter.consume_at(500, now));
+
+        // 一秒后补充 1000 个令牌，还清欠账后剩余 500 个
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Duration::ZERO, limiter.consume_at(500, later));
+        assert_eq!(Duration::from_millis(100), limiter.consume_at(100, later));
+    }
+}
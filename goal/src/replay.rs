@@ -0,0 +1,382 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ReplayError {
+    // 令牌时间戳超出允许的时间窗口
+    OutsideWindow,
+    // nonce 已被使用过
+    Replayed,
+    // 为了限制内存，较早的 nonce 已被淘汰，无法判断该令牌是否被使用过
+    Evicted,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ReplayError::OutsideWindow => "token timestamp outside window",
+            ReplayError::Replayed => "token nonce replayed",
+            ReplayError::Evicted => "token older than evicted nonces",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// 日志文件中过期的记录超过该数量且多于有效的记录时压缩日志文件
+const COMPACT_MIN_EXPIRED: usize = 1024;
+
+// 日志文件中记录 `floor` 的行，压缩时写在开头
+const FLOOR_PREFIX: &str = "floor ";
+
+struct SeenNonce {
+    timestamp: u64,
+    key: String,
+}
+
+// 记录时间窗口内已使用的 nonce。
+//
+// 超出时间窗口的令牌会直接被拒绝，因此只需记住窗口内的 nonce。
+// 容量已满时淘汰最早插入的 nonce，并把 `floor` 提升到被淘汰的时间戳，
+// 时间戳不大于 `floor` 的令牌都会被拒绝，这样淘汰不会带来重放的机会。
+pub struct ReplayGuard {
+    window_secs: u64,
+    capacity: usize,
+    entries: VecDeque<SeenNonce>,
+    keys: HashSet<String>,
+    floor: u64,
+    journal: Option<Journal>,
+}
+
+struct Journal {
+    path: PathBuf,
+    file: File,
+    // 日志文件中的记录数，包括已过期的
+    lines: usize,
+    compaction: Option<Compaction>,
+}
+
+// 在单独的线程中写入压缩后的临时文件，期间新的记录照常追加到原文件，同时暂存在 `pending` 中，
+// 线程结束后补写到临时文件，再替换原文件
+struct Compaction {
+    thread: JoinHandle<std::io::Result<File>>,
+    lines: usize,
+    pending: Vec<String>,
+}
+
+impl ReplayGuard {
+    pub fn new(window_secs: u64, capacity: usize) -> Self {
+        Self {
+            window_secs,
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+            keys: HashSet::new(),
+            floor: 0,
+            journal: None,
+        }
+    }
+
+    // 从日志文件恢复窗口内的 nonce 与 `floor`，压缩日志文件，之后每个被接受的 nonce 都会追加到日志文件。
+    // 过期的记录较多时在后台再次压缩，日志文件不会无限增长
+    pub fn with_journal(window_secs: u64, capacity: usize, path: &Path, now: u64) -> std::io::Result<Self> {
+        let mut guard = Self::new(window_secs, capacity);
+
+        let mut floor = 0;
+        if path.exists() {
+            let f = File::open(path)?;
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                if let Some(s) = line.strip_prefix(FLOOR_PREFIX) {
+                    floor = s.parse::<u64>().unwrap_or(0).max(floor);
+                    continue;
+                }
+                let mut parts = line.splitn(2, ' ');
+                let timestamp = parts.next().and_then(|s| s.parse::<u64>().ok());
+                let key = parts.next();
+                if let (Some(timestamp), Some(key)) = (timestamp, key) {
+                    let _ = guard.check_key(key, timestamp, now);
+                }
+            }
+        }
+        // 恢复 nonce 之后再提升，窗口内曾因容量被淘汰的 nonce 在重启后依然会被拒绝
+        guard.floor = guard.floor.max(floor);
+
+        let lines = guard.snapshot();
+        let file = write_snapshot(&tmp_path(path), &lines)?;
+        std::fs::rename(tmp_path(path), path)?;
+        guard.journal = Some(Journal {
+            path: path.to_path_buf(),
+            file,
+            lines: lines.len(),
+            compaction: None,
+        });
+        Ok(guard)
+    }
+
+    pub fn check(&mut self, user: &str, nonce: &str, timestamp: u64, now: u64) -> Result<(), ReplayError> {
+        let key = format!("{}:{}", user, nonce);
+        self.check_key(&key, timestamp, now)?;
+
+        if self.journal.is_some() {
+            self.append_journal(format!("{} {}", timestamp, key));
+        }
+
+        Ok(())
+    }
+
+    fn append_journal(&mut self, line: String) {
+        let due = self.journal.as_ref().is_some_and(|journal| {
+            let expired = journal.lines.saturating_sub(self.entries.len());
+            journal.compaction.is_none() && expired >= COMPACT_MIN_EXPIRED && expired > self.entries.len()
+        });
+        let snapshot = if due { Some(self.snapshot()) } else { None };
+
+        let journal = match self.journal.as_mut() {
+            Some(journal) => journal,
+            None => return,
+        };
+        match writeln!(journal.file, "{}", line) {
+            Ok(_) => journal.lines += 1,
+            Err(e) => tracing::error!("Failed to append nonce journal, err: {}", e),
+        }
+
+        if let Some(compaction) = journal.compaction.as_mut() {
+            compaction.pending.push(line);
+            if compaction.thread.is_finished() {
+                journal.finish_compaction();
+            }
+        } else if let Some(lines) = snapshot {
+            // 快照中已包含本条记录，无需补写
+            let tmp_path = tmp_path(&journal.path);
+            let count = lines.len();
+            let thread = std::thread::Builder::new()
+                .name("nonce-journal".to_string())
+                .spawn(move || write_snapshot(&tmp_path, &lines));
+            match thread {
+                Ok(thread) => journal.compaction = Some(Compaction { thread, lines: count, pending: Vec::new() }),
+                Err(e) => tracing::error!("Failed to compact nonce journal, err: {}", e),
+            }
+        }
+    }
+
+    // 日志文件压缩后的内容：`floor` 与窗口内的 nonce
+    fn snapshot(&self) -> Vec<String> {
+        let floor = Some(format!("{}{}", FLOOR_PREFIX, self.floor)).filter(|_| self.floor > 0);
+        floor.into_iter()
+            .chain(self.entries.iter().map(|entry| format!("{} {}", entry.timestamp, entry.key)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn check_key(&mut self, key: &str, timestamp: u64, now: u64) -> Result<(), ReplayError> {
+        if timestamp.abs_diff(now) > self.window_secs {
+            return Err(ReplayError::OutsideWindow);
+        }
+
+        self.expire(now);
+
+        if self.keys.contains(key) {
+            return Err(ReplayError::Replayed);
+        }
+        if timestamp <= self.floor {
+            return Err(ReplayError::Evicted);
+        }
+
+        while self.entries.len() >= self.capacity {
+            if let Some(evicted) = self.entries.pop_front() {
+                self.keys.remove(&evicted.key);
+                self.floor = self.floor.max(evicted.timestamp);
+            }
+        }
+
+        self.keys.insert(key.to_string());
+        self.entries.push_back(SeenNonce {
+            timestamp,
+            key: key.to_string(),
+        });
+
+        Ok(())
+    }
+
+    fn expire(&mut self, now: u64) {
+        while let Some(front) = self.entries.front() {
+            if front.timestamp + self.window_secs >= now {
+                break;
+            }
+            if let Some(expired) = self.entries.pop_front() {
+                self.keys.remove(&expired.key);
+            }
+        }
+    }
+}
+
+impl Journal {
+    // 补写压缩期间追加的记录，再用临时文件替换原文件；失败时继续使用原文件
+    fn finish_compaction(&mut self) {
+        let Compaction { thread, lines, pending } = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => return,
+        };
+        let ret = thread.join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("compaction thread panicked")))
+            .and_then(|mut file| {
+                for line in &pending {
+                    writeln!(file, "{}", line)?;
+                }
+                std::fs::rename(tmp_path(&self.path), &self.path)?;
+                Ok(file)
+            });
+
+        match ret {
+            Ok(file) => {
+                self.file = file;
+                self.lines = lines + pending.len();
+            }
+            Err(e) => tracing::error!("Failed to compact nonce journal, err: {}", e),
+        }
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.set_extension("tmp");
+    tmp_path
+}
+
+// 写入临时文件并同步到磁盘，返回的文件可以继续追加
+fn write_snapshot(tmp_path: &Path, lines: &[String]) -> std::io::Result<File> {
+    let mut tmp = File::create(tmp_path)?;
+    for line in lines {
+        writeln!(tmp, "{}", line)?;
+    }
+    tmp.sync_all()?;
+    Ok(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_test() {
+        let mut guard = ReplayGuard::new(60, 10);
+
+        assert_eq!(Ok(()), guard.check("alice", "n1", 1000, 1000));
+        assert_eq!(Err(ReplayError::Replayed), guard.check("alice", "n1", 1000, 1010));
+        // 同一个 nonce 属于不同用户时互不影响
+        assert_eq!(Ok(()), guard.check("bob", "n1", 1000, 1010));
+    }
+
+    #[test]
+    fn window_test() {
+        let mut guard = ReplayGuard::new(60, 10);
+
+        assert_eq!(Err(ReplayError::OutsideWindow), guard.check("alice", "n1", 1000, 1061));
+        assert_eq!(Err(ReplayError::OutsideWindow), guard.check("alice", "n1", 1061, 1000));
+        assert_eq!(Ok(()), guard.check("alice", "n1", 1000, 1060));
+
+        // 过期的 nonce 会被清理
+        assert_eq!(Ok(()), guard.check("alice", "n2", 1100, 1100));
+        assert_eq!(1, guard.len());
+    }
+
+    #[test]
+    fn capacity_test() {
+        let mut guard = ReplayGuard::new(60, 2);
+
+        assert_eq!(Ok(()), guard.check("alice", "n1", 1000, 1000));
+        assert_eq!(Ok(()), guard.check("alice", "n2", 1001, 1001));
+        assert_eq!(Ok(()), guard.check("alice", "n3", 1002, 1002));
+        assert_eq!(2, guard.len());
+
+        // "n1" 已被淘汰，但其时间戳不大于 floor，依然会被拒绝
+        assert_eq!(Err(ReplayError::Evicted), guard.check("alice", "n1", 1000, 1003));
+        assert_eq!(Ok(()), guard.check("alice", "n4", 1003, 1003));
+    }
+
+    #[test]
+    fn journal_test() {
+        let path = std::env::temp_dir().join(format!("goal_nonce_journal_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut guard = ReplayGuard::with_journal(60, 10, &path, 1000).unwrap();
+            assert_eq!(Ok(()), guard.check("alice", "n1", 1000, 1000));
+            assert_eq!(Ok(()), guard.check("alice", "n2", 900, 950));
+        }
+
+        // 模拟 goal 重启
+        let mut guard = ReplayGuard::with_journal(60, 10, &path, 1010).unwrap();
+        assert_eq!(1, guard.len());
+        assert_eq!(Err(ReplayError::Replayed), guard.check("alice", "n1", 1000, 1010));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // 压缩在后台进行，测试中等待其完成
+    impl ReplayGuard {
+        fn wait_compaction(&mut self) {
+            if let Some(journal) = self.journal.as_mut() {
+                if let Some(compaction) = journal.compaction.as_ref() {
+                    while !compaction.thread.is_finished() {
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                }
+                journal.finish_compaction();
+            }
+        }
+    }
+
+    #[test]
+    fn journal_floor_test() {
+        let path = std::env::temp_dir().join(format!("goal_nonce_journal_floor_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut guard = ReplayGuard::with_journal(60, 2, &path, 1000).unwrap();
+            assert_eq!(Ok(()), guard.check("alice", "n1", 1000, 1000));
+            assert_eq!(Ok(()), guard.check("alice", "n2", 1001, 1001));
+            assert_eq!(Ok(()), guard.check("alice", "n3", 1002, 1002));
+        }
+
+        // 重启时压缩日志文件，被淘汰的 "n1" 不再保留，但 `floor` 被保留下来
+        drop(ReplayGuard::with_journal(60, 2, &path, 1003).unwrap());
+        let mut guard = ReplayGuard::with_journal(60, 2, &path, 1004).unwrap();
+        assert_eq!(Err(ReplayError::Evicted), guard.check("alice", "n1", 1000, 1004));
+        assert_eq!(Err(ReplayError::Replayed), guard.check("alice", "n3", 1002, 1004));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn journal_compact_test() {
+        let path = std::env::temp_dir().join(format!("goal_nonce_journal_compact_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut guard = ReplayGuard::with_journal(60, 100, &path, 1000).unwrap();
+        for i in 0..3000 {
+            assert_eq!(Ok(()), guard.check("alice", &format!("n{}", i), 1000 + i, 1000 + i));
+            // 压缩线程较慢时期间追加的记录会暂时留在文件中，逐次等待使结果确定
+            guard.wait_compaction();
+        }
+        // 只保留窗口内的记录与不超过 `COMPACT_MIN_EXPIRED` 的过期记录
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= guard.len() + COMPACT_MIN_EXPIRED, "{} lines", lines);
+
+        drop(guard);
+        let mut guard = ReplayGuard::with_journal(60, 100, &path, 3999).unwrap();
+        assert_eq!(Err(ReplayError::Replayed), guard.check("alice", "n2999", 3999, 3999));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,201 @@
+// 活动的隧道会话，供管理接口查看与结束
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use transfer::traffic::RelayCounters;
+
+pub struct Session {
+    pub id: u64,
+    pub client_addr: SocketAddr,
+    // 未认证时为 `None`
+    pub user: Option<String>,
+    pub path: String,
+    started_at: Instant,
+    // 连接目标后才有，如 "example.com:443 (93.184.216.34:443)"
+    destination: Mutex<Option<String>>,
+    // 转发期间实时更新：`ws_to_tcp` 为 soccer ==> 目标，`tcp_to_ws` 为目标 ==> soccer
+    pub counters: RelayCounters,
+    cancel: CancellationToken,
+}
+
+impl Session {
+    pub fn set_destination(&self, destination: String) {
+        *self.destination.lock().unwrap() = Some(destination);
+    }
+
+    pub fn age(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    // 会话被结束时完成
+    pub async fn killed(&self) {
+        self.cancel.cancelled().await
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            client_addr: self.client_addr.to_string(),
+            user: self.user.clone(),
+            path: self.path.clone(),
+            destination: self.destination.lock().unwrap().clone(),
+            upload_bytes: self.counters.ws_to_tcp.bytes(),
+            upload_messages: self.counters.ws_to_tcp.messages(),
+            download_bytes: self.counters.tcp_to_ws.bytes(),
+            download_messages: self.counters.tcp_to_ws.messages(),
+            age_secs: self.age().as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client_addr: String,
+    pub user: Option<String>,
+    pub path: String,
+    pub destination: Option<String>,
+    pub upload_bytes: u64,
+    pub upload_messages: u64,
+    pub download_bytes: u64,
+    pub download_messages: u64,
+    pub age_secs: u64,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    // 各会话的令牌都是它的子令牌
+    cancel: CancellationToken,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `cancel` 被取消时结束所有会话，如退出时超过了等待期限
+    pub fn with_cancel(cancel: CancellationToken) -> Self {
+        Self {
+            cancel,
+            ..Self::default()
+        }
+    }
+
+    // 会话在返回的 `SessionGuard` 被丢弃时注销
+    pub fn register(self: &Arc<Self>, client_addr: SocketAddr, user: Option<String>, path: String) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            id,
+            client_addr,
+            user,
+            path,
+            started_at: Instant::now(),
+            destination: Mutex::new(None),
+            counters: RelayCounters::new(),
+            cancel: self.cancel.child_token(),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+
+        SessionGuard {
+            session,
+            sessions: self.clone(),
+        }
+    }
+
+    // 按 ID 排序
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut infos: Vec<SessionInfo> = self.sessions.lock().unwrap()
+            .values()
+            .map(|session| session.info())
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    // 返回会话是否存在
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct SessionGuard {
+    session: Arc<Session>,
+    sessions: Arc<Sessions>,
+}
+
+impl Deref for SessionGuard {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.session.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sessions_test() {
+        let sessions = Arc::new(Sessions::new());
+        let client_addr = "127.0.0.1:50000".parse().unwrap();
+
+        let first = sessions.register(client_addr, Some("alice".to_string()), "/goal".to_string());
+        let second = sessions.register(client_addr, None, "/goal".to_string());
+        first.set_destination("example.com:443".to_string());
+        first.counters.tcp_to_ws.add(10);
+
+        let infos = sessions.list();
+        assert_eq!(2, infos.len());
+        assert_eq!(Some("alice".to_string()), infos[0].user);
+        assert_eq!(Some("example.com:443".to_string()), infos[0].destination);
+        assert_eq!(10, infos[0].download_bytes);
+        assert_eq!(1, infos[0].download_messages);
+
+        assert!(sessions.kill(second.id));
+        second.killed().await;
+        assert!(!sessions.kill(100));
+
+        drop(first);
+        drop(second);
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_all_test() {
+        let cancel = CancellationToken::new();
+        let sessions = Arc::new(Sessions::with_cancel(cancel.clone()));
+        let session = sessions.register("127.0.0.1:50000".parse().unwrap(), None, "/goal".to_string());
+
+        cancel.cancel();
+        session.killed().await;
+    }
+}
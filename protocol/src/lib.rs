@@ -0,0 +1,207 @@
+// soccer 与 goal 之间的消息格式
+//
+// WebSocket 升级之后，双方只发送二进制消息，每条消息是一帧：
+//
+//     +---------+------+---------+
+//     | version | type | payload |
+//     |   u8    |  u8  |         |
+//     +---------+------+---------+
+//
+// 帧的长度由 WebSocket 消息确定。soccer 先发送 `Connect`，goal 连接目标后应答 `ConnectResult`，
+// 之后双方以 `Data` 转发数据；一个方向的数据发送完时先发送 `Stats` 再发送 `HalfClose`，
+// 读取出错时发送 `Reset`。版本不一致时拒绝解码，由调用方关闭连接
+
+mod reply;
+mod request;
+
+use std::fmt;
+use bytes::{Buf, BufMut, Bytes};
+
+pub use reply::ConnectReply;
+pub use request::{Address, DnsFamily, Flags, RequestHeader};
+
+// 2：请求头支持 IPv4 与 IPv6 地址、标志位以及首批数据
+pub const VERSION: u8 = 2;
+
+const HEADER_LEN: usize = 2;
+
+const CONNECT: u8 = 0x01;
+const CONNECT_RESULT: u8 = 0x02;
+const DATA: u8 = 0x03;
+const HALF_CLOSE: u8 = 0x04;
+const RESET: u8 = 0x05;
+const KEEPALIVE: u8 = 0x06;
+const STATS: u8 = 0x07;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Frame {
+    // 请求连接目标
+    Connect(RequestHeader),
+    // 连接目标的结果
+    ConnectResult(ConnectReply),
+    Data(Bytes),
+    // 发送方不再发送数据
+    HalfClose,
+    // 发送方的连接异常结束，接收方结束两个方向
+    Reset,
+    // 保持经过的代理不因空闲断开连接，接收方忽略
+    Keepalive,
+    // 发送方在这个方向上发送的数据量，在 `HalfClose` 之前发送
+    Stats { sent_bytes: u64 },
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let payload_len = match self {
+            Frame::Connect(header) => header.encoded_len(),
+            Frame::Data(data) => data.len(),
+            _ => 8,
+        };
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload_len);
+        buf.put_u8(VERSION);
+        buf.put_u8(self.frame_type());
+        match self {
+            Frame::Connect(header) => header.encode(&mut buf),
+            Frame::ConnectResult(reply) => buf.put_u8(*reply as u8),
+            Frame::Data(data) => buf.put_slice(data),
+            Frame::HalfClose | Frame::Reset | Frame::Keepalive => {}
+            Frame::Stats { sent_bytes } => buf.put_u64(*sent_bytes),
+        }
+        buf
+    }
+
+    // `Data` 帧的头部，其后紧接数据即为编码后的帧。
+    // 先写入头部再把数据直接读到其后，可以免去 `encode` 的复制
+    pub fn data_header() -> [u8; HEADER_LEN] {
+        [VERSION, DATA]
+    }
+
+    // `Data` 与 `buf` 共享内存
+    pub fn decode(mut buf: Bytes) -> Result<Self, DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::Malformed);
+        }
+        let version = buf.get_u8();
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let frame = match buf.get_u8() {
+            CONNECT => Frame::Connect(RequestHeader::decode(&mut buf)?),
+            CONNECT_RESULT if buf.remaining() >= 1 => Frame::ConnectResult(ConnectReply::from_rep(buf.get_u8())),
+            DATA => return Ok(Frame::Data(buf)),
+            HALF_CLOSE => Frame::HalfClose,
+            RESET => Frame::Reset,
+            KEEPALIVE => Frame::Keepalive,
+            STATS if buf.remaining() >= 8 => Frame::Stats { sent_bytes: buf.get_u64() },
+            CONNECT_RESULT | STATS => return Err(DecodeError::Malformed),
+            frame_type => return Err(DecodeError::UnknownType(frame_type)),
+        };
+        if buf.has_remaining() {
+            return Err(DecodeError::Malformed);
+        }
+        Ok(frame)
+    }
+
+    fn frame_type(&self) -> u8 {
+        match self {
+            Frame::Connect(_) => CONNECT,
+            Frame::ConnectResult(_) => CONNECT_RESULT,
+            Frame::Data(_) => DATA,
+            Frame::HalfClose => HALF_CLOSE,
+            Frame::Reset => RESET,
+            Frame::Keepalive => KEEPALIVE,
+            Frame::Stats { .. } => STATS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    // 长度与帧的类型不符
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            DecodeError::UnknownType(frame_type) => write!(f, "unknown frame type {}", frame_type),
+            DecodeError::Malformed => write!(f, "malformed frame"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn round_trip(frame: Frame) {
+        assert_eq!(Ok(frame.clone()), Frame::decode(Bytes::from(frame.encode())));
+    }
+
+    #[test]
+    fn round_trip_test() {
+        round_trip(Frame::Connect(RequestHeader::new(Address::Domain("example.com".to_string()), 443)));
+        round_trip(Frame::Connect(RequestHeader::new(Address::Domain(String::new()), 80)));
+        round_trip(Frame::Connect(RequestHeader::new("192.0.2.1".parse::<IpAddr>().unwrap().into(), 80)));
+        round_trip(Frame::Connect(RequestHeader {
+            address: "2001:db8::1".parse::<IpAddr>().unwrap().into(),
+            port: 443,
+            flags: Flags { udp: false, no_delay: true, dns_family: DnsFamily::Ipv6 },
+            early_data: Bytes::from_static(b"\x16\x03\x01\x02\x00\x01"),
+        }));
+        round_trip(Frame::ConnectResult(ConnectReply::Succeeded));
+        round_trip(Frame::ConnectResult(ConnectReply::ConnectionRefused));
+        round_trip(Frame::Data(Bytes::from_static(b"GET / HTTP/1.1\r\n")));
+        round_trip(Frame::Data(Bytes::new()));
+        round_trip(Frame::HalfClose);
+        round_trip(Frame::Reset);
+        round_trip(Frame::Keepalive);
+        round_trip(Frame::Stats { sent_bytes: u64::MAX });
+    }
+
+    #[test]
+    fn encode_test() {
+        let mut header = RequestHeader::new(Address::Domain("z.cn".to_string()), 80);
+        header.early_data = Bytes::from_static(b"GET");
+        let encoded = Frame::Connect(header).encode();
+        assert_eq!(vec![VERSION, CONNECT, 0x03, 4, b'z', b'.', b'c', b'n', 0, 80, 0, b'G', b'E', b'T'], encoded);
+
+        let header = RequestHeader::new("192.0.2.1".parse::<IpAddr>().unwrap().into(), 443);
+        assert_eq!(vec![VERSION, CONNECT, 0x01, 192, 0, 2, 1, 0x01, 0xbb, 0], Frame::Connect(header).encode());
+        assert_eq!(vec![VERSION, CONNECT_RESULT, 0x05], Frame::ConnectResult(ConnectReply::ConnectionRefused).encode());
+        assert_eq!([&Frame::data_header()[..], b"GET"].concat(), Frame::Data(Bytes::from_static(b"GET")).encode());
+    }
+
+    #[test]
+    fn decode_error_test() {
+        let decode = |bytes: &[u8]| Frame::decode(Bytes::copy_from_slice(bytes));
+
+        assert_eq!(Err(DecodeError::UnsupportedVersion(0)), decode(&[0, HALF_CLOSE]));
+        assert_eq!(Err(DecodeError::UnsupportedVersion(VERSION + 1)), decode(&[VERSION + 1, DATA, 1, 2, 3]));
+        assert_eq!(Err(DecodeError::UnknownType(0xff)), decode(&[VERSION, 0xff]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION]));
+        assert_eq!(Err(DecodeError::UnsupportedVersion(1)), decode(&[1, CONNECT, 0, 80, 0, 4, b'z', b'.', b'c', b'n']));
+        // 请求头不完整或地址类型错误，任何位置截断都不会 panic
+        let encoded = Frame::Connect(RequestHeader::new(Address::Domain("z.cn".to_string()), 80)).encode();
+        for len in 0..encoded.len() {
+            assert!(decode(&encoded[..len]).is_err());
+        }
+        let encoded = Frame::Connect(RequestHeader::new("2001:db8::1".parse::<IpAddr>().unwrap().into(), 80)).encode();
+        for len in 0..encoded.len() {
+            assert!(decode(&encoded[..len]).is_err());
+        }
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION, CONNECT, 0x02, 0, 80, 0]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION, CONNECT, 0x03, 2, 0xff, 0xfe, 0, 80, 0]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION, CONNECT_RESULT]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION, STATS, 0, 0]));
+        assert_eq!(Err(DecodeError::Malformed), decode(&[VERSION, HALF_CLOSE, 0]));
+    }
+}
@@ -0,0 +1,42 @@
+// 连接目标的结果，goal 在收到请求头后发给 soccer，由 soccer 转换为 SOCKS5 的应答
+//
+// 取值与 RFC 1928 中的 REP 字段一致：
+// https://www.ietf.org/rfc/rfc1928.txt
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConnectReply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl ConnectReply {
+    pub fn from_io_error(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => ConnectReply::ConnectionRefused,
+            std::io::ErrorKind::TimedOut => ConnectReply::TtlExpired,
+            std::io::ErrorKind::PermissionDenied => ConnectReply::NotAllowed,
+            _ => ConnectReply::HostUnreachable,
+        }
+    }
+
+    // SOCKS5 应答中的 REP 字段，未知的取值视为一般错误
+    pub fn from_rep(rep: u8) -> Self {
+        match rep {
+            0x00 => ConnectReply::Succeeded,
+            0x02 => ConnectReply::NotAllowed,
+            0x03 => ConnectReply::NetworkUnreachable,
+            0x04 => ConnectReply::HostUnreachable,
+            0x05 => ConnectReply::ConnectionRefused,
+            0x06 => ConnectReply::TtlExpired,
+            0x07 => ConnectReply::CommandNotSupported,
+            0x08 => ConnectReply::AddressTypeNotSupported,
+            _ => ConnectReply::GeneralFailure,
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use bytes::{Buf, BufMut, Bytes};
+use crate::DecodeError;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const FLAG_UDP: u8 = 0x01;
+const FLAG_NO_DELAY: u8 = 0x02;
+// 第 2、3 位为 `DnsFamily`
+const DNS_FAMILY_SHIFT: u8 = 2;
+const DNS_FAMILY_MASK: u8 = 0x03 << DNS_FAMILY_SHIFT;
+
+// soccer 请求连接的目标，以及随请求一起发送的首批数据（0-RTT），如 TLS 的 ClientHello
+//
+//     +------+---------+------+-------+------------+
+//     | atyp | address | port | flags | early data |
+//     |  u8  |         | u16  |  u8   |            |
+//     +------+---------+------+-------+------------+
+//
+// atyp 与 SOCKS5 一致：1 为 IPv4（4 字节），3 为域名（1 字节的长度与域名），4 为 IPv6（16 字节）。
+// 首批数据是帧中剩余的部分，可以为空
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RequestHeader {
+    pub address: Address,
+    pub port: u16,
+    pub flags: Flags,
+    pub early_data: Bytes,
+}
+
+impl RequestHeader {
+    pub fn new(address: Address, port: u16) -> Self {
+        Self {
+            address,
+            port,
+            flags: Flags::default(),
+            early_data: Bytes::new(),
+        }
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        let address_len = match &self.address {
+            Address::Ipv4(_) => 4,
+            Address::Ipv6(_) => 16,
+            Address::Domain(domain) => 1 + domain.len(),
+        };
+        1 + address_len + 2 + 1 + self.early_data.len()
+    }
+
+    // 域名不能超过 255 字节，soccer 从 SOCKS5 请求中取得的域名满足这一点
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match &self.address {
+            Address::Ipv4(ip) => {
+                buf.put_u8(ATYP_IPV4);
+                buf.put_slice(&ip.octets());
+            }
+            Address::Ipv6(ip) => {
+                buf.put_u8(ATYP_IPV6);
+                buf.put_slice(&ip.octets());
+            }
+            Address::Domain(domain) => {
+                let len = u8::try_from(domain.len()).expect("domain longer than 255 bytes");
+                buf.put_u8(ATYP_DOMAIN);
+                buf.put_u8(len);
+                buf.put_slice(domain.as_bytes());
+            }
+        }
+        buf.put_u16(self.port);
+        buf.put_u8(self.flags.to_bits());
+        buf.put_slice(&self.early_data);
+    }
+
+    // 取走 `buf` 中剩余的全部数据
+    pub(crate) fn decode(buf: &mut Bytes) -> Result<Self, DecodeError> {
+        let address = match take(buf, 1)?[0] {
+            ATYP_IPV4 => {
+                let octets: [u8; 4] = take(buf, 4)?.as_ref().try_into().unwrap();
+                Address::Ipv4(Ipv4Addr::from(octets))
+            }
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = take(buf, 16)?.as_ref().try_into().unwrap();
+                Address::Ipv6(Ipv6Addr::from(octets))
+            }
+            ATYP_DOMAIN => {
+                let len = take(buf, 1)?[0] as usize;
+                let domain = String::from_utf8(take(buf, len)?.to_vec()).map_err(|_| DecodeError::Malformed)?;
+                Address::Domain(domain)
+            }
+            _ => return Err(DecodeError::Malformed),
+        };
+        let port = take(buf, 2)?.get_u16();
+        let flags = Flags::from_bits(take(buf, 1)?[0])?;
+        let early_data = buf.split_off(0);
+        Ok(Self { address, port, flags, early_data })
+    }
+}
+
+// 长度不足时返回 `Malformed`
+fn take(buf: &mut Bytes, len: usize) -> Result<Bytes, DecodeError> {
+    if buf.remaining() < len {
+        return Err(DecodeError::Malformed);
+    }
+    Ok(buf.split_to(len))
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Address {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Address::Ipv4(ip),
+            IpAddr::V6(ip) => Address::Ipv6(ip),
+        }
+    }
+}
+
+// IPv6 地址不带方括号，与 `IpAddr` 的格式一致
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Ipv4(ip) => write!(f, "{}", ip),
+            Address::Ipv6(ip) => write!(f, "{}", ip),
+            Address::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Flags {
+    // 转发 UDP 而不是 TCP
+    pub udp: bool,
+    // 目标连接设置 TCP_NODELAY
+    pub no_delay: bool,
+    // 解析目标域名时使用的地址族
+    pub dns_family: DnsFamily,
+}
+
+impl Flags {
+    fn to_bits(self) -> u8 {
+        let mut bits = (self.dns_family as u8) << DNS_FAMILY_SHIFT;
+        if self.udp {
+            bits |= FLAG_UDP;
+        }
+        if self.no_delay {
+            bits |= FLAG_NO_DELAY;
+        }
+        bits
+    }
+
+    // 未定义的位需要新的协议版本，这里视为错误
+    fn from_bits(bits: u8) -> Result<Self, DecodeError> {
+        if bits & !(FLAG_UDP | FLAG_NO_DELAY | DNS_FAMILY_MASK) != 0 {
+            return Err(DecodeError::Malformed);
+        }
+        let dns_family = match (bits & DNS_FAMILY_MASK) >> DNS_FAMILY_SHIFT {
+            0 => DnsFamily::Any,
+            1 => DnsFamily::Ipv4,
+            2 => DnsFamily::Ipv6,
+            _ => return Err(DecodeError::Malformed),
+        };
+        Ok(Self {
+            udp: bits & FLAG_UDP != 0,
+            no_delay: bits & FLAG_NO_DELAY != 0,
+            dns_family,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DnsFamily {
+    // 同时查询 A 与 AAAA 记录
+    #[default]
+    Any = 0,
+    Ipv4 = 1,
+    Ipv6 = 2,
+}
+
+impl FromStr for DnsFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(DnsFamily::Any),
+            "ipv4" => Ok(DnsFamily::Ipv4),
+            "ipv6" => Ok(DnsFamily::Ipv6),
+            _ => Err(format!("invalid DNS family '{}', expected 'any', 'ipv4' or 'ipv6'", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_test() {
+        for udp in [false, true] {
+            for no_delay in [false, true] {
+                for dns_family in [DnsFamily::Any, DnsFamily::Ipv4, DnsFamily::Ipv6] {
+                    let flags = Flags { udp, no_delay, dns_family };
+                    assert_eq!(Ok(flags), Flags::from_bits(flags.to_bits()));
+                }
+            }
+        }
+        assert_eq!(0x0b, Flags { udp: true, no_delay: true, dns_family: DnsFamily::Ipv6 }.to_bits());
+        assert_eq!(Err(DecodeError::Malformed), Flags::from_bits(0x0c));
+        assert_eq!(Err(DecodeError::Malformed), Flags::from_bits(0x10));
+    }
+}
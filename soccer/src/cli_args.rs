@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use argh::FromArgs;
+use protocol::{DnsFamily, Flags};
+use transfer::{ReadBufferSize, RelayConfig, WebSocketLimits};
+
+fn default_server_address() -> String {
+    "127.0.0.1:18030".to_string()
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(description = "local SOCKS5 proxy which tunnels connections to goal")]
+pub struct CliArgs {
+    #[argh(
+        positional,
+        description = "goal URL such as 'ws://127.0.0.1:18030/goal', or just the address which implies path '/goal', default: '127.0.0.1:18030'",
+        default = "default_server_address()"
+    )]
+    pub server_address: String,
+
+    #[argh(option, description = "user name used to authenticate with goal")]
+    pub user: Option<String>,
+
+    #[argh(option, description = "secret used to sign the handshake token")]
+    pub secret: Option<String>,
+
+    #[argh(option, description = "address to serve Prometheus metrics on, such as '127.0.0.1:9180'")]
+    pub metrics_address: Option<SocketAddr>,
+
+    #[argh(
+        option,
+        description = "seconds to wait for in-flight tunnels on SIGTERM or SIGINT before closing them, default: 30",
+        default = "default_drain_timeout_secs()"
+    )]
+    pub drain_timeout_secs: u64,
+
+    #[argh(option, description = "seconds without data in both directions before closing a tunnel")]
+    pub idle_timeout_secs: Option<u64>,
+
+    #[argh(option, description = "seconds between WebSocket pings sent to goal")]
+    pub ping_interval_secs: Option<u64>,
+
+    #[argh(option, description = "seconds to wait for a pong before closing a tunnel, default: the ping interval")]
+    pub pong_timeout_secs: Option<u64>,
+
+    #[argh(option, description = "seconds without upload data before sending a keepalive message to goal")]
+    pub keepalive_interval_secs: Option<u64>,
+
+    #[argh(
+        switch,
+        description = "reply to the SOCKS5 client before connecting to goal and send its first bytes with the connect request; failures then close the connection instead of being reported"
+    )]
+    pub early_data: bool,
+
+    #[argh(switch, description = "ask goal to set TCP_NODELAY on the connection to the target")]
+    pub no_delay: bool,
+
+    #[argh(option, description = "address family goal uses to resolve target domains: 'any', 'ipv4' or 'ipv6', default: 'any'")]
+    pub dns_family: Option<DnsFamily>,
+
+    #[argh(option, description = "smallest buffer in bytes for reading from the client, default: 16384")]
+    pub min_read_buffer_size: Option<usize>,
+
+    #[argh(option, description = "largest buffer in bytes for reading from the client, default: 65536")]
+    pub max_read_buffer_size: Option<usize>,
+
+    #[argh(option, description = "largest WebSocket frame accepted from goal in bytes, default: 1048576")]
+    pub max_frame_size: Option<usize>,
+
+    #[argh(option, description = "largest WebSocket message accepted from goal in bytes, default: 1048576")]
+    pub max_message_size: Option<usize>,
+
+    #[argh(option, description = "bytes buffered before writing to goal, default: 131072")]
+    pub write_buffer_size: Option<usize>,
+
+    #[argh(option, description = "upper bound of the write buffer in bytes, must be greater than --write-buffer-size, default: 4194304")]
+    pub max_write_buffer_size: Option<usize>,
+}
+
+impl CliArgs {
+    pub fn goal_url(&self) -> String {
+        goal_url(&self.server_address)
+    }
+
+    pub fn relay_config(&self) -> RelayConfig {
+        let idle_timeout = self.idle_timeout_secs.map(Duration::from_secs);
+        let default = ReadBufferSize::default();
+        RelayConfig {
+            read_buffer: ReadBufferSize {
+                min: self.min_read_buffer_size.unwrap_or(default.min),
+                max: self.max_read_buffer_size.unwrap_or(default.max),
+            },
+            tcp_idle_timeout: idle_timeout,
+            ws_idle_timeout: idle_timeout,
+            ping_interval: self.ping_interval_secs.map(Duration::from_secs),
+            pong_timeout: self.pong_timeout_secs.map(Duration::from_secs),
+            keepalive_interval: self.keepalive_interval_secs.map(Duration::from_secs),
+            max_lifetime: None,
+        }
+    }
+
+    pub fn websocket_limits(&self) -> WebSocketLimits {
+        let default = WebSocketLimits::default();
+        WebSocketLimits {
+            max_frame_size: self.max_frame_size.unwrap_or(default.max_frame_size),
+            max_message_size: self.max_message_size.unwrap_or(default.max_message_size),
+            write_buffer_size: self.write_buffer_size.unwrap_or(default.write_buffer_size),
+            max_write_buffer_size: self.max_write_buffer_size.unwrap_or(default.max_write_buffer_size),
+        }
+    }
+
+    pub fn request_options(&self) -> RequestOptions {
+        RequestOptions {
+            flags: Flags {
+                udp: false,
+                no_delay: self.no_delay,
+                dns_family: self.dns_family.unwrap_or_default(),
+            },
+            early_data: self.early_data,
+        }
+    }
+
+    // user 和 secret 需同时提供
+    pub fn credentials(&self) -> Option<Credentials> {
+        match (&self.user, &self.secret) {
+            (Some(user), Some(secret)) => Some(Credentials {
+                user: user.clone(),
+                secret: secret.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// 每个隧道的请求头中的选项
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    pub flags: Flags,
+    // 先应答 SOCKS5 客户端，把客户端随后发送的数据放在请求头中
+    pub early_data: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub secret: String,
+}
+
+// "127.0.0.1:18030" ==> "ws://127.0.0.1:18030/goal"
+pub fn goal_url(server_address: &str) -> String {
+    if server_address.contains("://") {
+        server_address.to_string()
+    } else {
+        ["ws://", server_address, "/goal"].join("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goal_url_test() {
+        assert_eq!("ws://127.0.0.1:18030/goal", goal_url("127.0.0.1:18030"));
+        assert_eq!("ws://example.com/tunnel/a", goal_url("ws://example.com/tunnel/a"));
+    }
+}
@@ -1,40 +1,30 @@
+use protocol::Address;
+
 pub struct Destination {
-    address: Vec<u8>,
+    address: Address,
     port: u16,
 }
 
 impl Destination {
-    pub fn new(address: Vec<u8>, port: u16) -> Self {
+    pub fn new(address: Address, port: u16) -> Self {
         Self {
             address,
             port,
         }
     }
 
-    pub fn address(&self) -> &[u8] {
-        &self.address.as_slice()
+    pub fn address(&self) -> &Address {
+        &self.address
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
-    pub fn domain(&self) -> String {
-        String::from_utf8_lossy(&self.address).to_string()
-    }
-
     pub fn to_str(&self) -> String {
-        let domain = String::from_utf8_lossy(&self.address).to_owned();
-
-        format!("{}:{}", domain, self.port)
-    }
-}
-
-impl Default for Destination {
-    fn default() -> Self {
-        Self {
-            address: vec![],
-            port: 0,
+        match &self.address {
+            Address::Ipv6(ip) => format!("[{}]:{}", ip, self.port),
+            address => format!("{}:{}", address, self.port),
         }
     }
 }
@@ -1,3 +1,5 @@
+pub mod cli_args;
 mod destination;
+pub mod metrics;
 
 pub use destination::Destination;
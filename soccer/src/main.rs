@@ -3,22 +3,43 @@
 extern crate tokio;
 extern crate tokio_tungstenite;
 
-use bytes::{BufMut};
-use futures::{SinkExt, StreamExt};
+use bytes::Bytes;
+use futures::{SinkExt, Stream, StreamExt};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use std::error::Error;
-use std::net::SocketAddr;
-use log::{error, info};
-
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info};
+
+use auth_token::Token;
+use protocol::{Address, Frame, RequestHeader};
+use soccer::cli_args::{CliArgs, Credentials, RequestOptions};
 use soccer::Destination;
+use soccer::metrics;
+use transfer::{RelayConfig, WebSocketLimits};
+use transfer::traffic::RelayCounters;
+use transfer::shutdown::Shutdown;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let _ = env_logger::try_init();
 
-    let server_address = std::env::args().nth(1).unwrap_or("127.0.0.1:18030".to_string());
+    let args: CliArgs = argh::from_env();
+    let goal_url = args.goal_url();
+    let credentials = args.credentials();
+    let relay_config = Arc::new(args.relay_config());
+    relay_config.read_buffer.validate()?;
+    let websocket_limits = args.websocket_limits();
+    websocket_limits.validate()?;
+    let request_options = args.request_options();
+    if credentials.is_none() && (args.user.is_some() || args.secret.is_some()) {
+        return Err("Both --user and --secret are required for authentication".into());
+    }
     let addr = "127.0.0.1:8080".to_string();
     let addr = addr.parse::<SocketAddr>()?;
 
@@ -27,79 +48,194 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("监听失败");
     info!("Listening on: {}, pid: {}", addr, std::process::id());
 
+    if let Some(metrics_address) = args.metrics_address {
+        let metrics_listener = telemetry::bind(metrics_address)
+            .await
+            .expect("指标接口监听失败");
+        info!("Metrics listening on: {}", metrics_address);
+        tokio::spawn(telemetry::serve(metrics_listener));
+    }
+
+    let shutdown = Shutdown::new();
+    let terminate = transfer::shutdown::terminate_signal()?;
+    tokio::pin!(terminate);
+
     loop {
-        let (client_socket, client_addr) = listener.accept().await?;
+        // 接受连接的错误（如 EMFILE、ECONNABORTED）已在 `tcp_accept` 中记录，继续接受下一个连接
+        let (client_socket, client_addr) = tokio::select! {
+            conn = transfer::tcp_accept::tcp_accept(&listener) => match conn {
+                Some(conn) => conn,
+                None => continue,
+            },
+            _ = &mut terminate => break,
+        };
         info!("Accept a connection from {}", client_addr);
+        metrics::CONNECTIONS_ACCEPTED.inc();
 
-        let goal_addr = server_address.clone();
+        let goal_url = goal_url.clone();
 
-        tokio::spawn(process(client_socket, goal_addr));
+        shutdown.spawn(process(client_socket, goal_url, credentials.clone(), relay_config.clone(), websocket_limits, request_options, shutdown.clone()));
     }
-}
 
-async fn process(mut client_socket: TcpStream, goal_addr: String) {
-    recv_method_selection_message(&mut client_socket).await.unwrap();
-    send_method_selection_message(&mut client_socket).await.unwrap();
+    // 停止接受新连接，等待进行中的隧道结束
+    drop(listener);
+    shutdown.drain(Duration::from_secs(args.drain_timeout_secs)).await;
+    info!("Exited");
+    Ok(())
+}
 
-    let remote_dst: Destination = recv_request(&mut client_socket).await;
+async fn process(
+    mut client_socket: TcpStream,
+    goal_url: String,
+    credentials: Option<Credentials>,
+    relay_config: Arc<RelayConfig>,
+    websocket_limits: WebSocketLimits,
+    request_options: RequestOptions,
+    shutdown: Shutdown,
+) {
+    let remote_dst = match socks_handshake(&mut client_socket).await {
+        Ok(remote_dst) => remote_dst,
+        Err(e) => {
+            error!("SOCKS5 handshake failed, err: {}", e);
+            metrics::handshake_failed("socks");
+            return;
+        }
+    };
     let dest_str = remote_dst.to_str();
-    println!("destination: {}", dest_str);
+    info!("destination: {}", dest_str);
 
-    send_reply(&mut client_socket).await.unwrap();
+    // 提前应答时，客户端在 WebSocket 握手期间就会发送数据（如 TLS 的 ClientHello），
+    // 这些数据随请求头一起发送；之后的失败无法再告知客户端，只能关闭连接
+    let replied = request_options.early_data;
+    if replied && send_reply(&mut client_socket, REP_SUCCEEDED).await.is_err() {
+        return;
+    }
 
-    let addr = ["ws://".to_string(), goal_addr, "/goal".to_string()].join("");
+    let mut request = match goal_url.into_client_request() {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Invalid goal address, err: {:?}", e);
+            reply_failure(&mut client_socket, replied).await;
+            return;
+        }
+    };
+    if let Some(credentials) = credentials {
+        // 每次握手都生成新的令牌，goal 会拒绝重复使用的令牌
+        let token = Token::generate(&credentials.user, credentials.secret.as_bytes());
+        let header_value = HeaderValue::from_str(&token.to_header_value()).unwrap();
+        request.headers_mut().insert(auth_token::HEADER_NAME, header_value);
+    }
 
     // WebSocket handshake
-    let ret = tokio_tungstenite::connect_async(addr).await;
+    let connect_started_at = std::time::Instant::now();
+    let observe_connect = |result: &str| {
+        metrics::CONNECT_DURATION
+            .with_label_values(&[result])
+            .observe(connect_started_at.elapsed().as_secs_f64());
+    };
+    let ret = tokio_tungstenite::connect_async_with_config(request, Some(websocket_limits.to_config()), false).await;
     // 如果请求的路径与 goal 端的要求一致（比如 "/goal"），那么 goal 端会返回 101 Switching Protocols 相应
     // 如果不一致，那么 goal 端会返回 404 相应，此时只能终止处理流程
     if ret.is_err() {
         error!("Failed to connect to soccer, err: {:?}", ret);
+        observe_connect("error");
+        metrics::handshake_failed("websocket");
+        reply_failure(&mut client_socket, replied).await;
         return;
     }
 
     let (goal_stream, _resp) = ret.unwrap();
-    println!("resp: {:?}", _resp);
-    let (mut goal_write, goal_read) = goal_stream.split();
-    let (client_read, client_write) = client_socket.into_split();
+    debug!("resp: {:?}", _resp);
+    let (mut goal_write, mut goal_read) = goal_stream.split();
 
     // Send request header
-    let buf: Vec<u8> = encode_request_header(&remote_dst);
-    println!("buf: {:?}", buf);
-    let request_header_msg = Message::binary(buf);
-    goal_write.send(request_header_msg).await.unwrap();
-
-    // goal ==> client
-    let goal_to_client = tokio::spawn(async move {
-        transfer::ws_to_tcp(goal_read, client_write).await;
-    });
-
-    // client ==> goal
-    let client_to_goal = tokio::spawn(async move {
-        transfer::tcp_to_ws(client_read, goal_write).await;
-    });
-
-    let _ = tokio::join!(goal_to_client, client_to_goal);
-}
+    let mut request_header = RequestHeader::new(remote_dst.address().clone(), remote_dst.port());
+    request_header.flags = request_options.flags;
+    if request_options.early_data {
+        request_header.early_data = read_early_data(&client_socket);
+    }
+    debug!("request_header: {:?}", request_header);
+    // 首批数据随请求头发送，同样计入上传的流量
+    let counters = RelayCounters::new();
+    if !request_header.early_data.is_empty() {
+        counters.tcp_to_ws.add(request_header.early_data.len());
+    }
+    let request_header_msg = Message::binary(Frame::Connect(request_header).encode());
+    if let Err(e) = goal_write.send(request_header_msg).await {
+        error!("Failed to send request header, err: {:?}", e);
+        observe_connect("error");
+        metrics::handshake_failed("request_header");
+        reply_failure(&mut client_socket, replied).await;
+        return;
+    }
 
-fn encode_request_header(remote_dst: &Destination) -> Vec<u8> {
-    let mut buf: Vec<u8> = Vec::with_capacity(100);
+    // goal 连接目标之后才会应答，应答中的结果原样转告客户端
+    let rep = recv_goal_reply(&mut goal_read).await;
+    if rep != REP_SUCCEEDED {
+        observe_connect("error");
+        metrics::handshake_failed("rejected");
+    } else {
+        observe_connect("ok");
+    }
+    if rep != REP_SUCCEEDED {
+        info!("Connect {} failed, REP: {}", dest_str, rep);
+        if !replied {
+            let _ = send_reply(&mut client_socket, rep).await;
+        }
+        return;
+    }
+    if !replied && send_reply(&mut client_socket, REP_SUCCEEDED).await.is_err() {
+        return;
+    }
 
-    // 2 bytes, port
-    buf.put_u16(remote_dst.port());
+    let (client_read, client_write) = client_socket.into_split();
 
-    // 2 bytes, reserved
-    buf.put_u16(0);
+    // client <==> goal，超时或退出时超过了等待期限则向 goal 发送 Close 帧
+    let active_tunnel = metrics::ActiveTunnel::start();
+    let summary = transfer::relay(client_read, client_write, goal_read, goal_write, &relay_config, &counters, shutdown.closing()).await;
+    info!("Tunnel to {} ended, upload: {}, download: {}", dest_str, summary.tcp_to_ws, summary.ws_to_tcp);
+    drop(active_tunnel);
+    metrics::SESSION_DURATION.observe(connect_started_at.elapsed().as_secs_f64());
+}
 
-    // 2 bytes, domain length
-    buf.put_u16(remote_dst.address().len() as u16);
+// 协商认证方式并读取请求
+async fn socks_handshake(client_socket: &mut TcpStream) -> std::io::Result<Destination> {
+    recv_method_selection_message(client_socket).await?;
+    send_method_selection_message(client_socket).await?;
+    recv_request(client_socket).await
+}
 
-    // n bytes, domain
-    for &b in remote_dst.address() {
-        buf.put_u8(b);
+// goal 应答的结果与 SOCKS5 应答的 REP 字段一致
+async fn recv_goal_reply<R>(goal_read: &mut R) -> u8
+where
+    R: Stream<Item=Result<Message, WsError>> + Unpin,
+{
+    let reply = match goal_read.next().await {
+        Some(Ok(Message::Binary(payload))) => Frame::decode(Bytes::from(payload)),
+        other => {
+            error!("Unexpected reply from goal: {:?}", other);
+            return REP_GENERAL_FAILURE;
+        }
+    };
+    match reply {
+        Ok(Frame::ConnectResult(reply)) => reply as u8,
+        other => {
+            error!("Unexpected reply from goal: {:?}", other);
+            REP_GENERAL_FAILURE
+        }
     }
+}
 
-    buf
+// 不等待，只取走客户端已经发送的数据
+fn read_early_data(client_socket: &TcpStream) -> Bytes {
+    let mut buf = vec![0; EARLY_DATA_MAX];
+    match client_socket.try_read(&mut buf) {
+        Ok(n) => {
+            buf.truncate(n);
+            Bytes::from(buf)
+        }
+        Err(_) => Bytes::new(),
+    }
 }
 
 // +----+----------+----------+
@@ -110,11 +246,11 @@ fn encode_request_header(remote_dst: &Destination) -> Vec<u8> {
 async fn recv_method_selection_message(socket: &mut TcpStream) -> std::io::Result<usize> {
     let mut ver_buf = [0; 1];
     socket.read_exact(&mut ver_buf).await?;
-    println!("VER: {}", ver_buf[0]);
+    debug!("VER: {}", ver_buf[0]);
 
     let mut nmethods_buf = [0; 1];
     socket.read_exact(&mut nmethods_buf).await?;
-    println!("NMETHODS: {}", nmethods_buf[0]);
+    debug!("NMETHODS: {}", nmethods_buf[0]);
 
     let mut methods_buf = vec![0; nmethods_buf[0] as usize];
     socket.read_exact(&mut methods_buf).await
@@ -142,37 +278,52 @@ async fn send_method_selection_message(socket: &mut TcpStream) -> std::io::Resul
 // | 1  |  1  | X'00' |  1   | Variable |    2     |
 // +----+-----+-------+------+----------+----------+
 
-async fn recv_request(socket: &mut TcpStream) -> Destination {
+async fn recv_request(socket: &mut TcpStream) -> std::io::Result<Destination> {
     let mut buf = [0; 4];
-    socket.read_exact(&mut buf).await.unwrap();
-
-    println!("VER: {}, CMD: {}, ATYP: {}", buf[0], buf[1], buf[3]);
-
-    let address_type = buf[3];
-
-    if address_type == 3 {
-        let mut len_buf = [0];
-        socket.read_exact(&mut len_buf).await.unwrap();
-
-        let address_len = len_buf[0];
-
-        let mut address_buf = vec![0; address_len as usize];
-        socket.read_exact(&mut address_buf).await.unwrap();
-
-
-        let mut port_buf = [0; 2];
-        socket.read_exact(&mut port_buf).await.unwrap();
-
-        let port = (port_buf[0] as u16) * 256 + (port_buf[1] as u16);
-
-        let domain = String::from_utf8_lossy(&address_buf);
-        // 远程的域名和端口，域名需要解析成 IP 地址
-        println!("DST.ADDR: '{}', DST.PORT: {:?}", domain, port);
-
-        Destination::new(address_buf, port)
-    } else {
-        Destination::default()
-    }
+    socket.read_exact(&mut buf).await?;
+
+    debug!("VER: {}, CMD: {}, ATYP: {}", buf[0], buf[1], buf[3]);
+
+    let address = match buf[3] {
+        1 => {
+            let mut octets = [0; 4];
+            socket.read_exact(&mut octets).await?;
+            Address::Ipv4(Ipv4Addr::from(octets))
+        }
+        3 => {
+            let mut len_buf = [0];
+            socket.read_exact(&mut len_buf).await?;
+
+            let mut address_buf = vec![0; len_buf[0] as usize];
+            socket.read_exact(&mut address_buf).await?;
+
+            match String::from_utf8(address_buf) {
+                Ok(domain) => Address::Domain(domain),
+                Err(_) => {
+                    let _ = send_reply(socket, REP_GENERAL_FAILURE).await;
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "domain is not valid UTF-8"));
+                }
+            }
+        }
+        4 => {
+            let mut octets = [0; 16];
+            socket.read_exact(&mut octets).await?;
+            Address::Ipv6(Ipv6Addr::from(octets))
+        }
+        address_type => {
+            let _ = send_reply(socket, REP_ADDRESS_TYPE_NOT_SUPPORTED).await;
+            let msg = format!("address type {} not supported", address_type);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+        }
+    };
+
+    let mut port_buf = [0; 2];
+    socket.read_exact(&mut port_buf).await?;
+    let port = u16::from_be_bytes(port_buf);
+
+    // 远程的地址和端口，域名由 goal 解析
+    debug!("DST.ADDR: '{}', DST.PORT: {:?}", address, port);
+    Ok(Destination::new(address, port))
 }
 
 // +----+-----+-------+------+----------+----------+
@@ -191,10 +342,24 @@ async fn recv_request(socket: &mut TcpStream) -> Destination {
 // X'07' Command not supported
 // X'08' Address type not supported
 // X'09' to X'FF' unassigned
-async fn send_reply(socket: &mut TcpStream) -> std::io::Result<()> {
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// 随请求头发送的首批数据的上限
+const EARLY_DATA_MAX: usize = 16 << 10;
+
+// 已经提前应答时只关闭连接
+async fn reply_failure(socket: &mut TcpStream, replied: bool) {
+    if !replied {
+        let _ = send_reply(socket, REP_GENERAL_FAILURE).await;
+    }
+}
+
+async fn send_reply(socket: &mut TcpStream, rep: u8) -> std::io::Result<()> {
     let reply = [
         5,
-        0,
+        rep,
         0,
         1,
         1, 1, 1, 1,
@@ -0,0 +1,56 @@
+use telemetry::once_cell::sync::Lazy;
+use telemetry::prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+};
+
+pub static CONNECTIONS_ACCEPTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("soccer_connections_accepted_total", "SOCKS5 connections accepted").unwrap()
+});
+
+// reason 见 `handshake_failed` 的调用处
+pub static HANDSHAKE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("soccer_handshake_failures_total", "Tunnel handshakes that failed", &["reason"]).unwrap()
+});
+
+pub static ACTIVE_TUNNELS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("soccer_active_tunnels", "Tunnels currently open").unwrap()
+});
+
+// 从发起 WebSocket 握手到收到 goal 的应答，result: "ok" 或 "error"
+pub static CONNECT_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "soccer_connect_duration_seconds",
+        "Time to open a tunnel through goal",
+        &["result"],
+        telemetry::DURATION_BUCKETS.to_vec()
+    ).unwrap()
+});
+
+pub static SESSION_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "soccer_session_duration_seconds",
+        "Lifetime of tunnel sessions",
+        telemetry::DURATION_BUCKETS.to_vec()
+    ).unwrap()
+});
+
+pub fn handshake_failed(reason: &str) {
+    HANDSHAKE_FAILURES.with_label_values(&[reason]).inc();
+}
+
+// 存在期间计入 `ACTIVE_TUNNELS`，提前返回或 panic 时同样会减去
+pub struct ActiveTunnel(());
+
+impl ActiveTunnel {
+    pub fn start() -> Self {
+        ACTIVE_TUNNELS.inc();
+        Self(())
+    }
+}
+
+impl Drop for ActiveTunnel {
+    fn drop(&mut self) {
+        ACTIVE_TUNNELS.dec();
+    }
+}
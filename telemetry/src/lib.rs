@@ -0,0 +1,126 @@
+// 以 Prometheus 文本格式导出指标
+//
+// 各 crate 在默认的 registry 中注册自己的指标，goal 与 soccer 通过 `serve` 提供 "/metrics"
+
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use log::{debug, error};
+use prometheus::{Encoder, TextEncoder};
+
+pub use once_cell;
+pub use prometheus;
+
+// 请求头的最大长度
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+// 连接、会话等耗时的分桶，单位：秒
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+// 默认 registry 中全部指标的文本格式
+pub fn encode() -> String {
+    let mut buf = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        error!("Failed to encode metrics, err: {}", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("Metrics accept error, err: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = process(stream).await {
+                debug!("Metrics request from {} failed, err: {}", addr, e);
+            }
+        });
+    }
+}
+
+pub async fn bind(bind_address: SocketAddr) -> std::io::Result<TcpListener> {
+    TcpListener::bind(bind_address).await
+}
+
+async fn process(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+        if buf.len() >= MAX_REQUEST_LEN {
+            return Ok(());
+        }
+    }
+
+    let (status, body) = match request_line(&buf) {
+        Some(("GET", "/metrics")) => ("200 OK", encode()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response_head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, prometheus::TEXT_FORMAT, body.len(),
+    );
+    stream.write_all(response_head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// 请求行中的方法与路径（不含查询字符串）
+fn request_line(buf: &[u8]) -> Option<(&str, &str)> {
+    let line_end = buf.windows(2).position(|w| w == b"\r\n")?;
+    let line = std::str::from_utf8(&buf[..line_end]).ok()?;
+
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let path = target.split('?').next()?;
+    Some((method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::register_int_counter;
+
+    #[test]
+    fn request_line_test() {
+        assert_eq!(Some(("GET", "/metrics")), request_line(b"GET /metrics?x=1 HTTP/1.1\r\n\r\n"));
+        assert_eq!(None, request_line(b"GET /metrics"));
+    }
+
+    #[tokio::test]
+    async fn serve_test() {
+        let counter = register_int_counter!("telemetry_test_total", "Counter for the test").unwrap();
+        counter.inc_by(3);
+
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("telemetry_test_total 3"));
+    }
+}
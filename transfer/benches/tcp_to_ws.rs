@@ -0,0 +1,172 @@
+// tcp_to_ws 的吞吐量，与原先每次读取 100 字节、逐条 send 的实现，
+// 以及读到的数据经 `Frame::encode` 复制一次再发送的实现对比
+//
+// cargo bench -p transfer
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use protocol::Frame;
+use transfer::{Liveness, ReadBufferSize, RelayConfig, WebSocketLimits};
+use transfer::traffic::TrafficCounter;
+
+const TOTAL_BYTES: usize = 256 << 20;
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(addr));
+    (accepted.unwrap().0, connected.unwrap())
+}
+
+// 原先的实现，消息中只有数据
+async fn legacy_tcp_to_ws<R, W>(mut tcp_read: R, mut ws_write: W)
+where
+    R: AsyncRead + Unpin,
+    W: Sink<Message, Error=Error> + Unpin,
+{
+    let mut buf = vec![0; 100];
+    loop {
+        match tcp_read.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => ws_write.send(Message::binary(&buf[0..n])).await.unwrap(),
+        }
+    }
+    ws_write.send(Message::text("half_close")).await.unwrap();
+}
+
+// 重复使用读缓冲区，编码为消息时复制一次
+async fn copying_tcp_to_ws<R, W>(mut tcp_read: R, mut ws_write: W, size: usize)
+where
+    R: AsyncRead + Unpin,
+    W: Sink<Message, Error=Error> + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(size);
+    loop {
+        buf.reserve(size);
+        match tcp_read.read_buf(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let data = Frame::Data(buf.split().freeze());
+                ws_write.send(Message::binary(data.encode())).await.unwrap();
+            }
+        }
+    }
+    ws_write.send(Message::binary(Frame::HalfClose.encode())).await.unwrap();
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Variant {
+    Legacy,
+    Copying,
+    Relay,
+}
+
+// 经 TCP 连接写入 `TOTAL_BYTES`，由 `relay` 转为 WebSocket 消息，在另一端读完为止
+async fn measure(variant: Variant, read_buffer: ReadBufferSize) -> (Duration, usize) {
+    let legacy = variant == Variant::Legacy;
+    let (mut source, tcp_read) = tcp_pair().await;
+    let (ws_server, ws_client) = tcp_pair().await;
+    let config = Some(WebSocketLimits::default().to_config());
+    let ws_write = WebSocketStream::from_raw_socket(ws_server, Role::Server, config).await;
+    let mut ws_read = WebSocketStream::from_raw_socket(ws_client, Role::Client, config).await;
+
+    let started_at = Instant::now();
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0x5a; 64 << 10];
+        for _ in 0..TOTAL_BYTES / chunk.len() {
+            source.write_all(&chunk).await.unwrap();
+        }
+    });
+    let relay = tokio::spawn(async move {
+        match variant {
+            Variant::Legacy => legacy_tcp_to_ws(tcp_read, ws_write).await,
+            Variant::Copying => copying_tcp_to_ws(tcp_read, ws_write, read_buffer.max).await,
+            Variant::Relay => {
+                let config = RelayConfig { read_buffer, ..RelayConfig::default() };
+                transfer::tcp_to_ws(tcp_read, ws_write, &config, &Liveness::new(), &TrafficCounter::new()).await;
+            }
+        }
+    });
+
+    let (mut bytes, mut messages) = (0, 0);
+    while let Some(msg) = ws_read.next().await {
+        let payload = match msg.unwrap() {
+            Message::Binary(payload) => Bytes::from(payload),
+            _ => break,
+        };
+        let data = if legacy {
+            payload
+        } else {
+            match Frame::decode(payload).unwrap() {
+                Frame::Data(data) => data,
+                Frame::Stats { .. } => continue,
+                _ => break,
+            }
+        };
+        bytes += data.len();
+        messages += 1;
+    }
+    let elapsed = started_at.elapsed();
+    writer.await.unwrap();
+    relay.await.unwrap();
+    assert_eq!(TOTAL_BYTES, bytes);
+    (elapsed, messages)
+}
+
+// 从内存中读取，收到的消息直接丢弃，不经过 TCP 与 tungstenite，
+// 只比较读取数据并生成消息的开销，即数据是否被复制一次
+async fn measure_in_memory(variant: Variant, read_buffer: ReadBufferSize) -> (Duration, usize) {
+    let source = vec![0x5a; TOTAL_BYTES];
+    let tcp_read = source.as_slice();
+    let messages = Arc::new(AtomicUsize::new(0));
+    let counted = messages.clone();
+    let ws_write = futures::sink::unfold((), move |_, msg: Message| {
+        if matches!(msg, Message::Binary(_)) {
+            counted.fetch_add(1, Ordering::Relaxed);
+        }
+        async { Ok::<_, Error>(()) }
+    });
+    tokio::pin!(ws_write);
+
+    let started_at = Instant::now();
+    match variant {
+        Variant::Copying => copying_tcp_to_ws(tcp_read, ws_write, read_buffer.max).await,
+        _ => {
+            let config = RelayConfig { read_buffer, ..RelayConfig::default() };
+            transfer::tcp_to_ws(tcp_read, ws_write, &config, &Liveness::new(), &TrafficCounter::new()).await;
+        }
+    }
+    (started_at.elapsed(), messages.load(Ordering::Relaxed))
+}
+
+fn report(name: &str, (elapsed, messages): (Duration, usize)) {
+    let mib_per_sec = TOTAL_BYTES as f64 / (1 << 20) as f64 / elapsed.as_secs_f64();
+    println!("{:<24} {:>10.1} MiB/s {:>10} messages {:>8.0} ms", name, mib_per_sec, messages, elapsed.as_secs_f64() * 1000.0);
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let fixed_64k = ReadBufferSize { min: 64 << 10, max: 64 << 10 };
+        report("legacy (100 B, send)", measure(Variant::Legacy, ReadBufferSize::default()).await);
+        report("copying 64 KiB, send", measure(Variant::Copying, fixed_64k).await);
+        report("fixed 16 KiB", measure(Variant::Relay, ReadBufferSize { min: 16 << 10, max: 16 << 10 }).await);
+        report("fixed 64 KiB", measure(Variant::Relay, fixed_64k).await);
+        report("adaptive 16-64 KiB", measure(Variant::Relay, ReadBufferSize::default()).await);
+
+        println!("in memory, without TCP and tungstenite:");
+        report("copying 64 KiB", measure_in_memory(Variant::Copying, fixed_64k).await);
+        report("fixed 64 KiB", measure_in_memory(Variant::Relay, fixed_64k).await);
+    });
+}
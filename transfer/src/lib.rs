@@ -1,95 +1,958 @@
+pub mod metrics;
+pub mod shutdown;
 pub mod tcp_accept;
+pub mod traffic;
 
-use futures::{Sink, SinkExt, Stream, StreamExt};
-use log::{debug, error};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use bytes::{BufMut, Bytes};
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, warn};
+use protocol::Frame;
+use traffic::{EndReason, RelayCounters, RelaySummary, TrafficCounter, TransferSummary};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, Interval};
 use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
 type WsError = Error;
 
-pub async fn ws_to_tcp<R, W>(mut ws_read: R, mut tcp_write: W)
+// 隧道的超时与保活设置，`None` 表示不启用
+#[derive(Debug, Clone, Default)]
+pub struct RelayConfig {
+    // 读取 TCP 连接的缓冲区大小
+    pub read_buffer: ReadBufferSize,
+    // 多久没有从 TCP 连接读到数据算作空闲
+    pub tcp_idle_timeout: Option<Duration>,
+    // 多久没有从 WebSocket 收到数据算作空闲，Ping 与 Pong 不算。
+    // 两个方向都空闲时结束隧道，只配置了一个方向时另一个方向使用同一个时长
+    pub ws_idle_timeout: Option<Duration>,
+    // 发送 Ping 的间隔
+    pub ping_interval: Option<Duration>,
+    // 发送 Ping 后多久没有收到 Pong 时结束隧道，未配置时与 `ping_interval` 相同
+    pub pong_timeout: Option<Duration>,
+    // 这段时间内没有发送数据时发送 `Frame::Keepalive`，使中间的代理与 NAT 不会因空闲而断开连接，
+    // 对端收到后直接丢弃，不算作数据
+    pub keepalive_interval: Option<Duration>,
+    // 隧道的最长存活时间
+    pub max_lifetime: Option<Duration>,
+}
+
+// `relay` 两个方向共享的保活状态
+#[derive(Debug)]
+pub struct Liveness {
+    // `ws_to_tcp` 收到的 Pong 数，由 `tcp_to_ws` 用来判断 Ping 是否超时
+    pongs: AtomicU64,
+    // `ws_to_tcp` 结束后不再读取 WebSocket，收不到 Pong，`tcp_to_ws` 随即停止发送 Ping
+    ws_read_finished: AtomicBool,
+    started_at: Instant,
+    // 两个方向最后一次读到数据的时间，为 `started_at` 之后的毫秒数
+    tcp_read_at: AtomicU64,
+    ws_read_at: AtomicU64,
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn touch(&self, read_at: &AtomicU64) {
+        read_at.store(self.started_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // 一个方向的空闲期限到了之后，另一个方向空闲的期限；已经空闲时返回 `None`
+    fn other_idle_deadline(&self, other_read_at: &AtomicU64, idle: Option<Duration>) -> Option<Instant> {
+        let read_at = self.started_at + Duration::from_millis(other_read_at.load(Ordering::Relaxed));
+        let deadline = read_at + idle?;
+        (deadline > Instant::now()).then_some(deadline)
+    }
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            pongs: AtomicU64::new(0),
+            ws_read_finished: AtomicBool::new(false),
+            started_at: Instant::now(),
+            tcp_read_at: AtomicU64::new(0),
+            ws_read_at: AtomicU64::new(0),
+        }
+    }
+}
+
+// 读缓冲区的大小在 `min` 与 `max` 之间自适应：一次读满时加倍，读到的数据不足四分之一时减半。
+// 每次读到的数据作为一条消息发送，`max` 不应超过对端的 `max_message_size`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReadBufferSize {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ReadBufferSize {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min == 0 || self.min > self.max {
+            return Err("read buffer size must satisfy 0 < min <= max".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ReadBufferSize {
+    fn default() -> Self {
+        Self {
+            min: 16 << 10,
+            max: 64 << 10,
+        }
+    }
+}
+
+// WebSocket 的大小限制，goal 与 soccer 两端都使用。
+// 收到超过限制的帧或消息时以 1009 (Message Too Big) 关闭隧道，避免整条消息都缓存在内存中
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WebSocketLimits {
+    pub max_frame_size: usize,
+    pub max_message_size: usize,
+    // 写缓冲区超过该大小时写入底层的连接
+    pub write_buffer_size: usize,
+    // 写缓冲区的上限，须大于 `write_buffer_size`
+    pub max_write_buffer_size: usize,
+}
+
+impl WebSocketLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_frame_size == 0 || self.max_message_size == 0 {
+            return Err("max_frame_size and max_message_size must be greater than 0".to_string());
+        }
+        if self.max_write_buffer_size <= self.write_buffer_size {
+            return Err("max_write_buffer_size must be greater than write_buffer_size".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn to_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_frame_size: Some(self.max_frame_size),
+            max_message_size: Some(self.max_message_size),
+            write_buffer_size: self.write_buffer_size,
+            max_write_buffer_size: self.max_write_buffer_size,
+            ..WebSocketConfig::default()
+        }
+    }
+}
+
+impl Default for WebSocketLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 1 << 20,
+            max_message_size: 1 << 20,
+            write_buffer_size: 128 << 10,
+            max_write_buffer_size: 4 << 20,
+        }
+    }
+}
+
+// 隧道被提前结束的原因
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Teardown {
+    TcpIdle,
+    WsIdle,
+    PongTimeout,
+    MaxLifetime,
+    // 对端发送的帧或消息超过了 `WebSocketLimits`
+    MessageTooBig,
+    // 对端发送了 `Reset`
+    Reset,
+    // 对端发送的消息无法解码，如协议版本不一致
+    Protocol,
+    // `relay` 的 `stop` 完成
+    Stopped,
+}
+
+impl Teardown {
+    // 用作日志与指标的标签
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Teardown::TcpIdle | Teardown::WsIdle => "idle_timeout",
+            Teardown::PongTimeout => "pong_timeout",
+            Teardown::MaxLifetime => "max_lifetime",
+            Teardown::MessageTooBig => "message_too_big",
+            Teardown::Reset => "reset",
+            Teardown::Protocol => "protocol_error",
+            Teardown::Stopped => "stopped",
+        }
+    }
+
+    // 向对端发送的 Close 帧，对端没有应答 Pong 时不再发送
+    pub fn close_frame(&self) -> Option<CloseFrame<'static>> {
+        let (code, reason) = match self {
+            Teardown::TcpIdle | Teardown::WsIdle => (CloseCode::Normal, "idle timeout"),
+            Teardown::PongTimeout => return None,
+            Teardown::MaxLifetime => (CloseCode::Normal, "max lifetime reached"),
+            Teardown::MessageTooBig => (CloseCode::Size, "message too big"),
+            Teardown::Reset => (CloseCode::Normal, "reset by peer"),
+            Teardown::Protocol => (CloseCode::Protocol, "protocol error"),
+            Teardown::Stopped => (CloseCode::Away, "going away"),
+        };
+        Some(CloseFrame { code, reason: reason.into() })
+    }
+}
+
+impl fmt::Display for Teardown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Teardown::TcpIdle => "tcp idle timeout",
+            Teardown::WsIdle => "websocket idle timeout",
+            Teardown::PongTimeout => "pong timeout",
+            Teardown::MaxLifetime => "max lifetime reached",
+            Teardown::MessageTooBig => "message too big",
+            Teardown::Reset => "reset by peer",
+            Teardown::Protocol => "protocol error",
+            Teardown::Stopped => "stopped",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// `liveness` 与同一条隧道的 `tcp_to_ws` 共享，用于判断 Ping 是否超时；
+// `counter` 在转发期间实时累加，返回值只统计这一次调用
+pub async fn ws_to_tcp<R, W>(
+    mut ws_read: R,
+    mut tcp_write: W,
+    config: &RelayConfig,
+    liveness: &Liveness,
+    counter: &TrafficCounter,
+) -> TransferSummary
 where
     R: Stream<Item=Result<Message, WsError>> + Unpin,
     W: AsyncWrite + Unpin,
 {
-    while let Some(msg_ret) = StreamExt::next(&mut ws_read).await {
+    let mut idle_deadline = config.ws_idle_timeout.map(|idle| Instant::now() + idle);
+    let (mut received_bytes, mut messages) = (0u64, 0u64);
+    let end = loop {
+        let msg_ret = tokio::select! {
+            msg_ret = StreamExt::next(&mut ws_read) => msg_ret,
+            _ = sleep_until(idle_deadline) => {
+                // 另一个方向仍有数据时隧道不算空闲
+                let tcp_idle = config.tcp_idle_timeout.or(config.ws_idle_timeout);
+                match liveness.other_idle_deadline(&liveness.tcp_read_at, tcp_idle) {
+                    Some(deadline) => {
+                        idle_deadline = Some(deadline);
+                        continue
+                    }
+                    None => break EndReason::Teardown(Teardown::WsIdle),
+                }
+            }
+        };
+        let msg_ret = match msg_ret {
+            Some(msg_ret) => msg_ret,
+            None => break EndReason::Closed,
+        };
+
         match msg_ret {
             Ok(msg) => {
                 match msg {
-                    Message::Text(cmd) => {
-                        if cmd == "half_close" {
-                            debug!("Received half_close command from ws");
-                            break
+                    Message::Binary(payload) => {
+                        match Frame::decode(Bytes::from(payload)) {
+                            Ok(Frame::Data(data)) => {
+                                if let Err(e) = AsyncWriteExt::write_all(&mut tcp_write, &data).await {
+                                    error!("Failed to write to tcp, err: {:?}", e);
+                                    break EndReason::Error
+                                }
+                                received_bytes += data.len() as u64;
+                                messages += 1;
+                                counter.add(data.len());
+                                liveness.touch(&liveness.ws_read_at);
+                                metrics::WS_TO_TCP_BYTES.inc_by(data.len() as u64);
+                                idle_deadline = config.ws_idle_timeout.map(|idle| Instant::now() + idle);
+                            }
+                            Ok(Frame::HalfClose) => {
+                                debug!("Received HalfClose from ws");
+                                break EndReason::HalfClose
+                            }
+                            Ok(Frame::Reset) => {
+                                debug!("Received Reset from ws");
+                                break EndReason::Teardown(Teardown::Reset)
+                            }
+                            Ok(Frame::Keepalive) => {}
+                            Ok(Frame::Stats { sent_bytes }) => {
+                                if sent_bytes != received_bytes {
+                                    warn!("Peer sent {} bytes but {} bytes received", sent_bytes, received_bytes);
+                                }
+                            }
+                            Ok(frame) => {
+                                error!("Unexpected frame from ws: {:?}", frame);
+                                break EndReason::Teardown(Teardown::Protocol)
+                            }
+                            Err(e) => {
+                                error!("Failed to decode frame from ws, err: {}", e);
+                                break EndReason::Teardown(Teardown::Protocol)
+                            }
                         }
                     }
-                    Message::Binary(payload) => {
-                        AsyncWriteExt::write_all(&mut tcp_write, &payload).await.unwrap();
+                    // tungstenite 会自动应答 Pong
+                    Message::Ping(_) => {}
+                    Message::Pong(_) => {
+                        liveness.pongs.fetch_add(1, Ordering::Relaxed);
                     }
                     Message::Close(_) => {
                         debug!("Closed by CLOSE message");
-                        break
+                        break EndReason::Closed
                     }
                     unknown_msg => {
                         debug!("Closed by UNKNOWN message, {:?}", unknown_msg);
-                        break
+                        break EndReason::Error
                     }
                 }
             }
+            Err(Error::Capacity(e)) => {
+                error!("Received oversized WebSocket message, err: {}", e);
+                break EndReason::Teardown(Teardown::MessageTooBig)
+            }
             Err(e) => {
                 // Protocol(ResetWithoutClosingHandshake)
                 error!("Failed to receive WebSocket message, err: {:?}", e);
-                break
+                break EndReason::Error
             }
         }
-
-        println!("Waiting next ws message...");
-    }
+    };
 
     // ws 端已无数据，不会再向 `tcp_write` 写入数据，因此关闭 `tcp_write`，
     // 同时以此通知 TCP 连接的另一端。
     let _ = tcp_write.shutdown().await;
-    println!("ws_to_tcp finished");
+    liveness.ws_read_finished.store(true, Ordering::Relaxed);
+    debug!("ws_to_tcp finished, end: {}", end.as_str());
+    TransferSummary { bytes: received_bytes, messages, end }
 }
 
-pub async fn tcp_to_ws<R, W>(mut tcp_read: R, mut ws_write: W)
+// 配置了 `ping_interval` 时，在读取 TCP 连接的同时定期发送 Ping，`ws_to_tcp` 结束后不再发送
+pub async fn tcp_to_ws<R, W>(
+    mut tcp_read: R,
+    mut ws_write: W,
+    config: &RelayConfig,
+    liveness: &Liveness,
+    counter: &TrafficCounter,
+) -> TransferSummary
 where
     R: AsyncRead + Unpin,
     W: Sink<Message, Error=WsError> + Unpin,
 {
-    let mut buf = vec![0; 100];
-    loop {
-        match AsyncReadExt::read(&mut tcp_read, &mut buf).await {
+    let mut idle_deadline = config.tcp_idle_timeout.map(|idle| Instant::now() + idle);
+    let mut ping_interval = config.ping_interval
+        .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    let pong_timeout = config.pong_timeout.or(config.ping_interval).unwrap_or_default();
+    // 等待 Pong 的期限，以及发送 Ping 时已收到的 Pong 数
+    let mut pong_deadline: Option<(Instant, u64)> = None;
+    let mut keepalive_interval = config.keepalive_interval
+        .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    // 上一个间隔内是否发送过数据
+    let mut sent_recently = false;
+
+    let mut buf = ReadBuffer::new(config.read_buffer);
+    let (mut sent_bytes, mut messages) = (0u64, 0u64);
+    // 读取 TCP 连接出错时发送 `Reset`，否则发送 `HalfClose`
+    let mut reset = false;
+    let end = loop {
+        let read_ret = tokio::select! {
+            read_ret = buf.read(&mut tcp_read) => read_ret,
+            _ = sleep_until(idle_deadline) => {
+                let ws_idle = config.ws_idle_timeout.or(config.tcp_idle_timeout);
+                match liveness.other_idle_deadline(&liveness.ws_read_at, ws_idle) {
+                    Some(deadline) => {
+                        idle_deadline = Some(deadline);
+                        continue
+                    }
+                    None => break EndReason::Teardown(Teardown::TcpIdle),
+                }
+            }
+            _ = tick(&mut ping_interval) => {
+                if liveness.ws_read_finished.load(Ordering::Relaxed) {
+                    ping_interval = None;
+                    pong_deadline = None;
+                } else if pong_deadline.is_none() {
+                    if let Err(e) = SinkExt::send(&mut ws_write, Message::Ping(Vec::new())).await {
+                        error!("Failed to send PING message, err: {:?}", e);
+                        break EndReason::Error
+                    }
+                    pong_deadline = Some((Instant::now() + pong_timeout, liveness.pongs.load(Ordering::Relaxed)));
+                }
+                continue
+            }
+            _ = tick(&mut keepalive_interval) => {
+                if !std::mem::take(&mut sent_recently) {
+                    if let Err(e) = SinkExt::send(&mut ws_write, Message::binary(Frame::Keepalive.encode())).await {
+                        error!("Failed to send Keepalive, err: {:?}", e);
+                        break EndReason::Error
+                    }
+                }
+                continue
+            }
+            _ = sleep_until(pong_deadline.map(|(deadline, _)| deadline)) => {
+                let (_, sent_at_pongs) = pong_deadline.take().unwrap();
+                if liveness.ws_read_finished.load(Ordering::Relaxed) {
+                    ping_interval = None;
+                } else if liveness.pongs.load(Ordering::Relaxed) == sent_at_pongs {
+                    break EndReason::Teardown(Teardown::PongTimeout);
+                }
+                continue
+            }
+        };
+
+        match read_ret {
             Ok(0) => {
                 // when read() returns Ok(0), this signifies that the stream is closed.
                 // Any further calls to read() will complete immediately with Ok(0).
                 // With TcpStream instances, this signifies that the read half of the socket is closed.
                 debug!("tcp read close: Ok(0)");
-                break
+                break EndReason::Eof
             }
             Ok(n) => {
                 debug!("tcp read n: {}", n);
-                let msg = Message::binary(&buf[0..n]);
-                SinkExt::send(&mut ws_write, msg).await.unwrap();
+                let mut batched = n;
+                let mut send_ret = SinkExt::feed(&mut ws_write, Message::Binary(buf.split())).await;
+                let mut batched_messages = 1;
+                // 已经到达的数据先放入写缓冲区，读不到数据时再一起 flush；
+                // 读到结束或出错时留给下一次读取处理
+                while send_ret.is_ok() && batched < config.read_buffer.max {
+                    match buf.read(&mut tcp_read).now_or_never() {
+                        Some(Ok(n)) if n > 0 => {
+                            batched += n;
+                            batched_messages += 1;
+                            send_ret = SinkExt::feed(&mut ws_write, Message::Binary(buf.split())).await;
+                        }
+                        _ => break,
+                    }
+                }
+                if send_ret.is_ok() {
+                    send_ret = SinkExt::flush(&mut ws_write).await;
+                }
+                if let Err(e) = send_ret {
+                    error!("Failed to send to ws, err: {:?}", e);
+                    break EndReason::Error
+                }
+                sent_bytes += batched as u64;
+                messages += batched_messages;
+                counter.add_batch(batched, batched_messages);
+                sent_recently = true;
+                liveness.touch(&liveness.tcp_read_at);
+                metrics::TCP_TO_WS_BYTES.inc_by(batched as u64);
+                idle_deadline = config.tcp_idle_timeout.map(|idle| Instant::now() + idle);
             }
             Err(e) => {
                 // 连接到 baidu.com 可能会出现连接重置错误：
                 // Connection reset by peer (os error 54)
                 debug!("tcp read err: {}", e);
-                break
+                reset = true;
+                break EndReason::Error
+            }
+        }
+    };
+
+    // 被提前结束时由 `relay` 发送 Close 帧
+    let send_ret = match end {
+        EndReason::Teardown(_) => Ok(()),
+        _ if reset => SinkExt::send(&mut ws_write, Message::binary(Frame::Reset.encode())).await,
+        _ => {
+            let stats = Frame::Stats { sent_bytes };
+            match SinkExt::feed(&mut ws_write, Message::binary(stats.encode())).await {
+                Ok(_) => SinkExt::send(&mut ws_write, Message::binary(Frame::HalfClose.encode())).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if let Err(e) = send_ret {
+        // 可能的错误：
+        // AlreadyClosed
+        // Os { code: 32, kind: BrokenPipe, message: "Broken pipe" }
+        error!("Failed to send HalfClose or Reset, err: {:?}", e);
+    }
+
+    debug!("tcp_to_ws finished, end: {}", end.as_str());
+    TransferSummary { bytes: sent_bytes, messages, end }
+}
+
+// 在 TCP 连接与 WebSocket 之间双向转发，直到两个方向都结束。
+// 任一方向超时、超过最长存活时间或 `stop` 先完成（如退出时超过了等待期限、会话被结束）时，
+// 两个方向一起结束，并向对端发送 Close 帧。
+// `counters` 是这条隧道专用的计数器，返回的汇总按其中的计数，
+// 因此在转发之前发送的数据（如随请求头发送的首批数据）可以先记入其中
+pub async fn relay<TR, TW, WR, WW, S>(
+    tcp_read: TR,
+    tcp_write: TW,
+    ws_read: WR,
+    mut ws_write: WW,
+    config: &RelayConfig,
+    counters: &RelayCounters,
+    stop: S,
+) -> RelaySummary
+where
+    TR: AsyncRead + Unpin,
+    TW: AsyncWrite + Unpin,
+    WR: Stream<Item=Result<Message, WsError>> + Unpin,
+    WW: Sink<Message, Error=WsError> + Unpin,
+    S: Future<Output=()>,
+{
+    let liveness = Liveness::new();
+    let lifetime_deadline = config.max_lifetime.map(|lifetime| Instant::now() + lifetime);
+    let mut tcp_to_ws_end = None;
+    let mut ws_to_tcp_end = None;
+
+    let teardown = tokio::select! {
+        ret = async {
+            // 任一方向被提前结束时不再等待另一个方向
+            let end = |summary: TransferSummary, slot: &mut Option<EndReason>| {
+                *slot = Some(summary.end);
+                summary.end.teardown().map_or(Ok(()), Err)
+            };
+            tokio::try_join!(
+                async { end(tcp_to_ws(tcp_read, &mut ws_write, config, &liveness, &counters.tcp_to_ws).await, &mut tcp_to_ws_end) },
+                async { end(ws_to_tcp(ws_read, tcp_write, config, &liveness, &counters.ws_to_tcp).await, &mut ws_to_tcp_end) },
+            )
+        } => ret.err(),
+        _ = stop => Some(Teardown::Stopped),
+        _ = sleep_until(lifetime_deadline) => Some(Teardown::MaxLifetime),
+    };
+
+    // 未结束的方向随隧道一起被提前结束
+    let summary = |end: Option<EndReason>, counter: &TrafficCounter| {
+        TransferSummary::from_counter(counter, end.unwrap_or_else(|| EndReason::Teardown(teardown.unwrap())))
+    };
+    let summary = RelaySummary {
+        tcp_to_ws: summary(tcp_to_ws_end, &counters.tcp_to_ws),
+        ws_to_tcp: summary(ws_to_tcp_end, &counters.ws_to_tcp),
+        teardown,
+    };
+
+    let close_frame = match teardown.as_ref().and_then(Teardown::close_frame) {
+        Some(close_frame) => close_frame,
+        None => return summary,
+    };
+    debug!("Tearing down, reason: {}", close_frame.reason);
+    match tokio::time::timeout(shutdown::CLOSE_TIMEOUT, SinkExt::send(&mut ws_write, Message::Close(Some(close_frame)))).await {
+        Ok(Ok(_)) => debug!("Sent CLOSE message"),
+        Ok(Err(e)) => error!("Failed to send CLOSE message, err: {:?}", e),
+        Err(_) => error!("Failed to send CLOSE message, timed out"),
+    }
+    summary
+}
+
+// 每次读取前预留 `Data` 帧的头部，读到的数据紧接其后，
+// 取出的缓冲区即是编码后的帧，直接作为 `Message::Binary` 发送，数据不再复制
+struct ReadBuffer {
+    buf: Vec<u8>,
+    size: usize,
+    range: ReadBufferSize,
+}
+
+impl ReadBuffer {
+    fn new(range: ReadBufferSize) -> Self {
+        Self {
+            buf: Vec::new(),
+            size: range.min,
+            range,
+        }
+    }
+
+    // 最多读取 `size` 字节，并根据读到的数据量调整下一次的大小
+    async fn read<R>(&mut self, reader: &mut R) -> io::Result<usize>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // 上次取出后缓冲区为空；上次的读取被取消或没有读到数据时头部已经写入
+        if self.buf.is_empty() {
+            self.buf.reserve_exact(Frame::data_header().len() + self.size);
+            self.buf.extend_from_slice(&Frame::data_header());
+        } else {
+            self.buf.reserve_exact(self.size);
+        }
+        let n = reader.read_buf(&mut (&mut self.buf).limit(self.size)).await?;
+        if n == self.size {
+            self.size = (self.size * 2).min(self.range.max);
+        } else if n < self.size / 4 {
+            self.size = (self.size / 2).max(self.range.min);
+        }
+        Ok(n)
+    }
+
+    // 取出帧头与已读到的数据，即编码后的 `Data` 帧
+    fn split(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+// 未设置期限时一直等待
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    // 服务端与客户端的 WebSocket，`server_config` 只用于服务端
+    async fn ws_pair(server_config: Option<WebSocketConfig>) -> (WebSocketStream<TcpStream>, WebSocketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server, client) = tokio::join!(listener.accept(), TcpStream::connect(addr));
+        let server = WebSocketStream::from_raw_socket(server.unwrap().0, Role::Server, server_config).await;
+        let client = WebSocketStream::from_raw_socket(client.unwrap(), Role::Client, None).await;
+        (server, client)
+    }
+
+    // TCP 连接的另一端保持打开但不发送数据，返回结束原因
+    async fn relay_with<S>(server: WebSocketStream<TcpStream>, config: &RelayConfig, stop: S) -> Option<Teardown>
+    where
+        S: Future<Output=()>,
+    {
+        let (tcp_stream, tcp_peer) = tokio::io::duplex(1024);
+        let (tcp_read, tcp_write) = tokio::io::split(tcp_stream);
+        let (ws_write, ws_read) = server.split();
+        let summary = relay(tcp_read, tcp_write, ws_read, ws_write, config, &RelayCounters::new(), stop).await;
+        drop(tcp_peer);
+        summary.teardown
+    }
+
+    async fn next_close_code(client: &mut WebSocketStream<TcpStream>) -> CloseCode {
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => return frame.code,
+                Some(Ok(Message::Ping(_))) => continue,
+                other => panic!("unexpected message: {:?}", other),
             }
         }
     }
 
-    let half_close_msg = Message::text("half_close");
-    match SinkExt::send(&mut ws_write, half_close_msg).await {
-        Ok(_) => {},
-        Err(e) => {
-            // 可能的错误：
-            // AlreadyClosed
-            // Os { code: 32, kind: BrokenPipe, message: "Broken pipe" }
-            error!("Failed to send 'half_close' message, err: {:?}", e);
+    #[tokio::test]
+    async fn relay_stop_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let teardown = relay_with(server, &RelayConfig::default(), async {}).await;
+
+        assert_eq!(Some(Teardown::Stopped), teardown);
+        assert_eq!(CloseCode::Away, next_close_code(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn relay_idle_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let config = RelayConfig {
+            tcp_idle_timeout: Some(Duration::from_millis(50)),
+            ..RelayConfig::default()
+        };
+        let teardown = relay_with(server, &config, futures::future::pending()).await;
+
+        assert_eq!(Some(Teardown::TcpIdle), teardown);
+        assert_eq!(CloseCode::Normal, next_close_code(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn relay_one_way_idle_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let (tcp_stream, mut tcp_peer) = tokio::io::duplex(1024);
+        let (tcp_read, tcp_write) = tokio::io::split(tcp_stream);
+        let (ws_write, ws_read) = server.split();
+        let config = RelayConfig {
+            tcp_idle_timeout: Some(Duration::from_millis(50)),
+            ws_idle_timeout: Some(Duration::from_millis(50)),
+            ..RelayConfig::default()
+        };
+        // 只有下载方向有数据，持续的时间超过空闲时长
+        let peer = async {
+            for _ in 0..8 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                tcp_peer.write_all(b"data").await.unwrap();
+            }
+            tcp_peer.shutdown().await.unwrap();
+            tcp_peer
+        };
+        let reader = async {
+            let mut received = Vec::new();
+            loop {
+                let payload = match client.next().await {
+                    Some(Ok(Message::Binary(payload))) => payload,
+                    other => panic!("unexpected message: {:?}", other),
+                };
+                match Frame::decode(Bytes::from(payload)).unwrap() {
+                    Frame::Data(data) => received.extend_from_slice(&data),
+                    Frame::Stats { .. } => {}
+                    Frame::HalfClose => return received,
+                    other => panic!("unexpected frame: {:?}", other),
+                }
+            }
+        };
+        let counters = RelayCounters::new();
+        let relay = relay(tcp_read, tcp_write, ws_read, ws_write, &config, &counters, futures::future::pending());
+        let (_tcp_peer, received, summary) = tokio::join!(peer, reader, relay);
+
+        assert_eq!(&b"data".repeat(8)[..], &received[..]);
+        assert_eq!(EndReason::Eof, summary.tcp_to_ws.end);
+        // 下载结束之后两个方向都空闲
+        assert_eq!(Some(Teardown::WsIdle), summary.teardown);
+    }
+
+    #[tokio::test]
+    async fn relay_max_lifetime_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let config = RelayConfig {
+            ping_interval: Some(Duration::from_millis(10)),
+            max_lifetime: Some(Duration::from_millis(100)),
+            ..RelayConfig::default()
+        };
+        // 客户端一直在读，会应答 Ping
+        let (teardown, close_code) = tokio::join!(
+            relay_with(server, &config, futures::future::pending()),
+            next_close_code(&mut client),
+        );
+
+        assert_eq!(Some(Teardown::MaxLifetime), teardown);
+        assert_eq!(CloseCode::Normal, close_code);
+    }
+
+    #[tokio::test]
+    async fn relay_pong_timeout_test() {
+        // 客户端不读取，不会应答 Ping
+        let (server, _client) = ws_pair(None).await;
+        let config = RelayConfig {
+            ping_interval: Some(Duration::from_millis(10)),
+            pong_timeout: Some(Duration::from_millis(30)),
+            ..RelayConfig::default()
+        };
+        let teardown = relay_with(server, &config, futures::future::pending()).await;
+
+        assert_eq!(Some(Teardown::PongTimeout), teardown);
+    }
+
+    #[tokio::test]
+    async fn relay_half_close_ping_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let (tcp_stream, mut tcp_peer) = tokio::io::duplex(1024);
+        let (tcp_read, tcp_write) = tokio::io::split(tcp_stream);
+        let (ws_write, ws_read) = server.split();
+        let config = RelayConfig {
+            ping_interval: Some(Duration::from_millis(10)),
+            pong_timeout: Some(Duration::from_millis(20)),
+            ..RelayConfig::default()
+        };
+        // 客户端先结束上传，下载持续的时间超过 Ping 的超时
+        client.send(Message::binary(Frame::HalfClose.encode())).await.unwrap();
+        let peer = async {
+            for _ in 0..5 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                tcp_peer.write_all(b"data").await.unwrap();
+            }
+            drop(tcp_peer);
+        };
+        let reader = async {
+            let mut received = Vec::new();
+            loop {
+                let payload = match client.next().await {
+                    Some(Ok(Message::Binary(payload))) => payload,
+                    Some(Ok(Message::Ping(_))) => continue,
+                    other => panic!("unexpected message: {:?}", other),
+                };
+                match Frame::decode(Bytes::from(payload)).unwrap() {
+                    Frame::Data(data) => received.extend_from_slice(&data),
+                    Frame::Stats { .. } => {}
+                    Frame::HalfClose => return received,
+                    other => panic!("unexpected frame: {:?}", other),
+                }
+            }
+        };
+        let counters = RelayCounters::new();
+        let relay = relay(tcp_read, tcp_write, ws_read, ws_write, &config, &counters, futures::future::pending());
+        let (_, received, summary) = tokio::join!(peer, reader, relay);
+
+        assert_eq!(None, summary.teardown);
+        assert_eq!(EndReason::Eof, summary.tcp_to_ws.end);
+        assert_eq!(&b"data".repeat(5)[..], &received[..]);
+    }
+
+    #[tokio::test]
+    async fn relay_message_too_big_test() {
+        let limits = WebSocketLimits {
+            max_message_size: 1024,
+            ..WebSocketLimits::default()
+        };
+        let (server, mut client) = ws_pair(Some(limits.to_config())).await;
+        client.send(Message::binary(Frame::Data(Bytes::from(vec![0; 512])).encode())).await.unwrap();
+        client.send(Message::binary(Frame::Data(Bytes::from(vec![0; 2048])).encode())).await.unwrap();
+        let teardown = relay_with(server, &RelayConfig::default(), futures::future::pending()).await;
+
+        assert_eq!(Some(Teardown::MessageTooBig), teardown);
+        assert_eq!(CloseCode::Size, next_close_code(&mut client).await);
+    }
+
+    #[test]
+    fn websocket_limits_test() {
+        assert!(WebSocketLimits::default().validate().is_ok());
+        let limits = WebSocketLimits {
+            write_buffer_size: 4 << 20,
+            ..WebSocketLimits::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_buffer_test() {
+        let range = ReadBufferSize { min: 16, max: 64 };
+        let mut buf = ReadBuffer::new(range);
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut reader = data.as_slice();
+
+        // 读满时加倍，不超过 max
+        for size in [16, 32, 64, 64] {
+            assert_eq!(size, buf.read(&mut reader).await.unwrap());
         }
+        assert_eq!([&Frame::data_header()[..], &data[..176]].concat(), buf.split());
+
+        // 读到的数据不足四分之一时减半，不低于 min
+        let mut reader: &[u8] = &[1, 2, 3];
+        assert_eq!(3, buf.read(&mut reader).await.unwrap());
+        assert_eq!(32, buf.size);
+    }
+
+    #[tokio::test]
+    async fn tcp_to_ws_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let (tcp_stream, mut tcp_peer) = tokio::io::duplex(256 << 10);
+        let data: Vec<u8> = (0..(1 << 20)).map(|i| (i % 251) as u8).collect();
+        let writer = async {
+            tcp_peer.write_all(&data).await.unwrap();
+            drop(tcp_peer);
+        };
+        let reader = async {
+            let mut received = Vec::new();
+            loop {
+                let payload = match client.next().await {
+                    Some(Ok(Message::Binary(payload))) => payload,
+                    other => panic!("unexpected message: {:?}", other),
+                };
+                match Frame::decode(Bytes::from(payload)).unwrap() {
+                    Frame::Data(data) => {
+                        assert!(data.len() <= ReadBufferSize::default().max);
+                        received.extend_from_slice(&data);
+                    }
+                    Frame::Stats { sent_bytes } => assert_eq!(received.len() as u64, sent_bytes),
+                    Frame::HalfClose => return received,
+                    other => panic!("unexpected frame: {:?}", other),
+                }
+            }
+        };
+        let config = RelayConfig::default();
+        let liveness = Liveness::new();
+        let counter = TrafficCounter::new();
+        let (_, summary, received) = tokio::join!(writer, tcp_to_ws(tcp_stream, server, &config, &liveness, &counter), reader);
+        assert_eq!(data, received);
+        assert_eq!(EndReason::Eof, summary.end);
+        assert_eq!(1 << 20, summary.bytes);
+        assert_eq!((summary.bytes, summary.messages), (counter.bytes(), counter.messages()));
+    }
+
+    #[tokio::test]
+    async fn relay_summary_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let (tcp_stream, mut tcp_peer) = tokio::io::duplex(1024);
+        let (tcp_read, tcp_write) = tokio::io::split(tcp_stream);
+        let (ws_write, ws_read) = server.split();
+        let counters = RelayCounters::new();
+        let peer = async {
+            tcp_peer.write_all(b"pong").await.unwrap();
+            for data in [&b"ping"[..], b" ping"] {
+                client.send(Message::binary(Frame::Data(Bytes::from_static(data)).encode())).await.unwrap();
+            }
+            client.send(Message::binary(Frame::HalfClose.encode())).await.unwrap();
+            let mut received = Vec::new();
+            tcp_peer.read_to_end(&mut received).await.unwrap();
+            // 计数在转发期间实时更新
+            assert_eq!(9, counters.ws_to_tcp.bytes());
+            tcp_peer.shutdown().await.unwrap();
+            drop(tcp_peer);
+            received
+        };
+        let config = RelayConfig::default();
+        let relay = relay(tcp_read, tcp_write, ws_read, ws_write, &config, &counters, futures::future::pending());
+        let (received, summary) = tokio::join!(peer, relay);
+
+        assert_eq!(b"ping ping", &received[..]);
+        assert!(summary.is_clean());
+        assert_eq!(None, summary.teardown);
+        assert_eq!(TransferSummary { bytes: 9, messages: 2, end: EndReason::HalfClose }, summary.ws_to_tcp);
+        assert_eq!(TransferSummary { bytes: 4, messages: 1, end: EndReason::Eof }, summary.tcp_to_ws);
     }
 
-    println!("tcp_to_ws finished");
+    #[tokio::test]
+    async fn keepalive_test() {
+        let (server, mut client) = ws_pair(None).await;
+        let (tcp_stream, mut tcp_peer) = tokio::io::duplex(1024);
+        let config = RelayConfig {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            ..RelayConfig::default()
+        };
+        let peer = async {
+            tokio::time::sleep(Duration::from_millis(70)).await;
+            tcp_peer.write_all(b"data").await.unwrap();
+            drop(tcp_peer);
+        };
+        let reader = async {
+            let mut frames = Vec::new();
+            loop {
+                let payload = match client.next().await {
+                    Some(Ok(Message::Binary(payload))) => payload,
+                    other => panic!("unexpected message: {:?}", other),
+                };
+                match Frame::decode(Bytes::from(payload)).unwrap() {
+                    Frame::HalfClose => return frames,
+                    frame => frames.push(frame),
+                }
+            }
+        };
+        let liveness = Liveness::new();
+        let counter = TrafficCounter::new();
+        let (_, frames, summary) = tokio::join!(peer, reader, tcp_to_ws(tcp_stream, server, &config, &liveness, &counter));
+
+        // 空闲期间发送 Keepalive，不计入数据
+        let keepalives = frames.iter().filter(|frame| matches!(frame, Frame::Keepalive)).count();
+        assert!(keepalives >= 2, "{:?}", frames);
+        assert!(frames.contains(&Frame::Data(Bytes::from_static(b"data"))));
+        assert_eq!((4, 1), (summary.bytes, summary.messages));
+    }
+
+    #[tokio::test]
+    async fn relay_reset_test() {
+        let (server, mut client) = ws_pair(None).await;
+        client.send(Message::binary(Frame::Reset.encode())).await.unwrap();
+        let teardown = relay_with(server, &RelayConfig::default(), futures::future::pending()).await;
+
+        assert_eq!(Some(Teardown::Reset), teardown);
+        assert_eq!("reset", EndReason::Teardown(Teardown::Reset).as_str());
+        assert!(!EndReason::Teardown(Teardown::Reset).is_clean());
+        assert_eq!(CloseCode::Normal, next_close_code(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn relay_unsupported_version_test() {
+        let (server, mut client) = ws_pair(None).await;
+        client.send(Message::binary(vec![protocol::VERSION + 1, 0x03, 0])).await.unwrap();
+        let teardown = relay_with(server, &RelayConfig::default(), futures::future::pending()).await;
+
+        assert_eq!(Some(Teardown::Protocol), teardown);
+        assert_eq!(CloseCode::Protocol, next_close_code(&mut client).await);
+    }
 }
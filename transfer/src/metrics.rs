@@ -0,0 +1,10 @@
+use telemetry::once_cell::sync::Lazy;
+use telemetry::prometheus::{register_int_counter_vec, IntCounter, IntCounterVec};
+
+static TRANSFER_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("transfer_bytes_total", "Bytes relayed between TCP and WebSocket", &["direction"]).unwrap()
+});
+
+pub static TCP_TO_WS_BYTES: Lazy<IntCounter> = Lazy::new(|| TRANSFER_BYTES.with_label_values(&["tcp_to_ws"]));
+
+pub static WS_TO_TCP_BYTES: Lazy<IntCounter> = Lazy::new(|| TRANSFER_BYTES.with_label_values(&["ws_to_tcp"]));
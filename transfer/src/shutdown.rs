@@ -0,0 +1,118 @@
+// 优雅退出
+//
+// 收到 SIGTERM 或 SIGINT 后停止接受新连接，等待进行中的连接结束；
+// 超过期限时通知剩余的隧道向对端发送 Close 帧，再等待一小段时间后退出
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// 超过期限后，等待隧道发送 Close 帧并结束的时长
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 处理连接的任务，退出时等待其结束
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output=()> + Send + 'static,
+    {
+        self.tracker.spawn(task);
+    }
+
+    // 超过期限时取消，可以用来创建子令牌
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    // 超过期限时完成，用作 `relay` 的 `stop`，隧道收到后发送 Close 并结束
+    pub fn closing(&self) -> impl Future<Output=()> + Send + 'static {
+        let cancel = self.cancel.clone();
+        async move { cancel.cancelled().await }
+    }
+
+    // 进行中的任务数
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+
+    // 调用前应已停止接受新连接。返回时仍未结束的任务不再等待
+    pub async fn drain(&self, deadline: Duration) {
+        self.tracker.close();
+        if !self.is_empty() {
+            info!("Draining {} connections, deadline: {:?}", self.len(), deadline);
+        }
+        if tokio::time::timeout(deadline, self.tracker.wait()).await.is_ok() {
+            return;
+        }
+
+        warn!("Drain deadline exceeded, closing {} connections", self.len());
+        self.cancel.cancel();
+        if tokio::time::timeout(CLOSE_TIMEOUT, self.tracker.wait()).await.is_err() {
+            warn!("{} connections not closed in time", self.len());
+        }
+    }
+}
+
+// 注册 SIGTERM 与 SIGINT，返回的 future 在收到其中之一时完成
+pub fn terminate_signal() -> io::Result<impl Future<Output=()>> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn drain_waits_for_tasks_test() {
+        let shutdown = Shutdown::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let task_finished = finished.clone();
+        shutdown.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            task_finished.store(true, Ordering::SeqCst);
+        });
+
+        shutdown.drain(Duration::from_secs(5)).await;
+        assert!(finished.load(Ordering::SeqCst));
+        assert!(!shutdown.token().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn drain_closes_after_deadline_test() {
+        let shutdown = Shutdown::new();
+        let closing = shutdown.closing();
+        shutdown.spawn(closing);
+
+        shutdown.drain(Duration::from_millis(50)).await;
+        assert!(shutdown.token().is_cancelled());
+        assert!(shutdown.is_empty());
+    }
+}
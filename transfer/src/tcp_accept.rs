@@ -1,11 +1,43 @@
+use std::io;
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
+use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use log::error;
 
+// 可以接受连接的监听器：TCP 与 Unix 套接字
+pub trait Listen {
+    type Stream;
+    type Addr;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, Self::Addr)>>;
+}
+
+impl Listen for TcpListener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, Self::Addr)>> {
+        TcpListener::poll_accept(self, cx)
+    }
+}
+
+impl Listen for UnixListener {
+    type Stream = UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, Self::Addr)>> {
+        UnixListener::poll_accept(self, cx)
+    }
+}
+
+pub async fn tcp_accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
+    accept(listener).await
+}
+
 // Reference:
 // https://github.com/tokio-rs/axum/blob/axum-v0.7.5/axum/src/serve.rs#L388
-pub async fn tcp_accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
-    match listener.accept().await {
+pub async fn accept<L: Listen>(listener: &L) -> Option<(L::Stream, L::Addr)> {
+    match std::future::poll_fn(|cx| listener.poll_accept(cx)).await {
         Ok(conn) => {
             Some(conn)
         }
@@ -25,7 +57,7 @@ pub async fn tcp_accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr
             // > and then the listener will sleep for 1 second.
             //
             // hyper allowed customizing this but axum does not.
-            error!("TCP accept error, err: {}", e);
+            error!("Accept error, err: {}", e);
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             None
         }
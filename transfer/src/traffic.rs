@@ -0,0 +1,144 @@
+// 隧道的流量统计
+//
+// 转发期间由 `TrafficCounter` 实时累加，可以在其他任务中读取，用于会话日志、配额与指标；
+// 转发结束时返回 `TransferSummary`，包括结束的原因
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::Teardown;
+
+// 一个方向转发的数据量与消息数，克隆的句柄共享同一组计数
+#[derive(Debug, Clone, Default)]
+pub struct TrafficCounter {
+    inner: Arc<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    bytes: AtomicU64,
+    messages: AtomicU64,
+}
+
+impl TrafficCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.inner.messages.load(Ordering::Relaxed)
+    }
+
+    // 一条消息及其中的数据，也用于记录不经 `transfer` 转发的数据，如随请求头发送的首批数据
+    pub fn add(&self, bytes: usize) {
+        self.add_batch(bytes, 1);
+    }
+
+    pub(crate) fn add_batch(&self, bytes: usize, messages: u64) {
+        self.inner.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.messages.fetch_add(messages, Ordering::Relaxed);
+    }
+}
+
+// `relay` 两个方向的计数，每条隧道新建一组
+#[derive(Debug, Clone, Default)]
+pub struct RelayCounters {
+    pub tcp_to_ws: TrafficCounter,
+    pub ws_to_tcp: TrafficCounter,
+}
+
+impl RelayCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// 一个方向结束的原因
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EndReason {
+    // TCP 连接读到结束
+    Eof,
+    // 收到对端的 HalfClose
+    HalfClose,
+    // 收到 Close 帧或 WebSocket 已关闭
+    Closed,
+    // 读写出错
+    Error,
+    // 被提前结束
+    Teardown(Teardown),
+}
+
+impl EndReason {
+    // 数据已完整转发
+    pub fn is_clean(&self) -> bool {
+        matches!(self, EndReason::Eof | EndReason::HalfClose | EndReason::Closed)
+    }
+
+    pub fn teardown(&self) -> Option<Teardown> {
+        match self {
+            EndReason::Teardown(teardown) => Some(*teardown),
+            _ => None,
+        }
+    }
+
+    // 用作日志与指标的标签
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndReason::Eof => "eof",
+            EndReason::HalfClose => "half_close",
+            EndReason::Closed => "closed",
+            EndReason::Error => "error",
+            EndReason::Teardown(teardown) => teardown.as_str(),
+        }
+    }
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndReason::Teardown(teardown) => write!(f, "{}", teardown),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TransferSummary {
+    pub bytes: u64,
+    pub messages: u64,
+    pub end: EndReason,
+}
+
+impl TransferSummary {
+    pub(crate) fn from_counter(counter: &TrafficCounter, end: EndReason) -> Self {
+        Self {
+            bytes: counter.bytes(),
+            messages: counter.messages(),
+            end,
+        }
+    }
+}
+
+impl fmt::Display for TransferSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes in {} messages, {}", self.bytes, self.messages, self.end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RelaySummary {
+    pub tcp_to_ws: TransferSummary,
+    pub ws_to_tcp: TransferSummary,
+    // 隧道被提前结束的原因，两个方向都正常结束时为 `None`
+    pub teardown: Option<Teardown>,
+}
+
+impl RelaySummary {
+    pub fn is_clean(&self) -> bool {
+        self.tcp_to_ws.end.is_clean() && self.ws_to_tcp.end.is_clean()
+    }
+}